@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+
+use tokio::io;
+
+/// Lists and dictionaries nested deeper are rejected, as decoding them recursively could
+/// overflow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single bencoded value
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are kept sorted, as required by the format
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if `self` is a dictionary
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict().and_then(|dict| dict.get(key.as_bytes()))
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+/// Bencodes `value`
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_into(value, &mut buf);
+    buf
+}
+
+fn encode_into(value: &Value, buf: &mut Vec<u8>) {
+    match value {
+        Value::Integer(i) => buf.extend_from_slice(format!("i{i}e").as_bytes()),
+        Value::Bytes(bytes) => encode_bytes(bytes, buf),
+        Value::List(list) => {
+            buf.push(b'l');
+            for item in list {
+                encode_into(item, buf);
+            }
+            buf.push(b'e');
+        }
+        Value::Dict(dict) => {
+            buf.push(b'd');
+            for (key, item) in dict {
+                encode_bytes(key, buf);
+                encode_into(item, buf);
+            }
+            buf.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Decodes a single bencoded value spanning the whole of `data`
+pub fn decode(data: &[u8]) -> io::Result<Value> {
+    let mut decoder = Decoder {
+        data,
+        pos: 0,
+        depth: 0,
+    };
+    let value = decoder.value()?;
+
+    if decoder.pos != data.len() {
+        return Err(invalid_data("Trailing data after bencoded value."));
+    }
+    Ok(value)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Lists and dictionaries the current value is nested in
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> io::Result<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| invalid_data("Unexpected end of bencoded data."))
+    }
+
+    /// Enters a list or a dictionary, which decreases `depth` again once it ends
+    ///
+    /// Errors end decoding altogether, so that they don't need to restore it
+    fn enter_container(&mut self) -> io::Result<()> {
+        if self.depth == MAX_DEPTH {
+            return Err(invalid_data("Bencoded data nested too deeply."));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Integer(self.integer_until(b'e')?))
+            }
+            b'l' => {
+                self.enter_container()?;
+                let mut list = vec![];
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.enter_container()?;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value()?;
+                    if dict.insert(key, value).is_some() {
+                        return Err(invalid_data("Duplicate dictionary key."));
+                    }
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            _ => Err(invalid_data("Unexpected byte in bencoded data.")),
+        }
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = usize::try_from(self.integer_until(b':')?)
+            .map_err(|_| invalid_data("Negative byte string length."))?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid_data("Byte string longer than the remaining data."))?;
+
+        let bytes = self.data[self.pos..end].to_vec();
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Parses a decimal integer terminated by `terminator`, consuming the terminator
+    fn integer_until(&mut self, terminator: u8) -> io::Result<i64> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|byte| *byte == terminator)
+            .ok_or_else(|| invalid_data("Unterminated integer."))?;
+        let digits = std::str::from_utf8(&self.data[self.pos..self.pos + len])
+            .map_err(|_| invalid_data("Invalid integer."))?;
+
+        // Leading zeros and negative zero aren't allowed
+        let unsigned = digits.strip_prefix('-').unwrap_or(digits);
+        if unsigned.is_empty()
+            || !unsigned.bytes().all(|byte| byte.is_ascii_digit())
+            || (unsigned.starts_with('0') && digits.len() > 1)
+        {
+            return Err(invalid_data("Invalid integer."));
+        }
+
+        let integer = digits
+            .parse()
+            .map_err(|_| invalid_data("Integer out of range."))?;
+        self.pos += len + 1;
+        Ok(integer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_values() {
+        assert_eq!(encode(&Value::Integer(-42)), b"i-42e");
+        assert_eq!(encode(&"spam".into()), b"4:spam");
+        assert_eq!(
+            encode(&Value::List(vec!["spam".into(), 42.into()])),
+            b"l4:spami42ee"
+        );
+
+        let dict = BTreeMap::from([
+            (b"spam".to_vec(), "eggs".into()),
+            (b"cow".to_vec(), "moo".into()),
+        ]);
+        assert_eq!(encode(&Value::Dict(dict)), b"d3:cow3:moo4:spam4:eggse");
+    }
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode(b"i0e").unwrap(), Value::Integer(0));
+        assert_eq!(decode(b"0:").unwrap(), Value::Bytes(vec![]));
+
+        let value = decode(b"d4:spaml1:a1:bee").unwrap();
+        assert_eq!(
+            value.get("spam").unwrap().as_list().unwrap(),
+            &["a".into(), "b".into()]
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = b"d8:announce15:tcp://localhost4:infod6:lengthi10e4:name4:test5:otherli1ei2eeee";
+        assert_eq!(encode(&decode(data).unwrap()), data);
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        assert!(decode(b"i-0e").is_err());
+        assert!(decode(b"i03e").is_err());
+        assert!(decode(b"ie").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(b"l1:a").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+        assert!(decode(b"d1:ai1e1:ai2ee").is_err());
+
+        // Would overflow the stack if nothing limited nesting
+        let nested = [vec![b'l'; 200_000], vec![b'e'; 200_000]].concat();
+        let err = decode(&nested).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let nested = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        assert!(decode(&nested).is_ok());
+    }
+}
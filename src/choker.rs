@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+use tokio::io;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::peer_wire::PeerId;
+
+/// How often the best peers are unchoked again
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves to another peer every this many rounds (30 seconds)
+const OPTIMISTIC_UNCHOKE_ROUNDS: usize = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// Transfer rates with a peer over the last round, in bytes per second
+pub struct PeerRates {
+    /// From the client to the peer
+    pub upload: f64,
+    /// From the peer to the client
+    pub download: f64,
+}
+
+#[derive(Default)]
+struct PeerState {
+    /// Tells the task serving the leech whether it's choked, while the leech is connected
+    choked: Option<watch::Sender<bool>>,
+    interested: bool,
+    /// Bytes transferred during the current round
+    uploaded: u64,
+    downloaded: u64,
+    rates: PeerRates,
+}
+
+impl PeerState {
+    fn is_unchoked(&self) -> bool {
+        self.choked.as_ref().is_some_and(|choked| !*choked.borrow())
+    }
+
+    fn set_choked(&self, choked: bool) {
+        if let Some(sender) = &self.choked {
+            sender.send_if_modified(|current| std::mem::replace(current, choked) != choked);
+        }
+    }
+}
+
+/// Decides which leeches get served, with tit-for-tat: leeches which upload to the client the
+/// fastest are unchoked, plus one random leech so that new peers get a chance
+pub struct Choker {
+    /// Leeches unchoked at once, the optimistic unchoke included
+    slots: usize,
+    peers: HashMap<PeerId, PeerState>,
+    optimistic: Option<PeerId>,
+    rounds: usize,
+    last_round: Instant,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: HashMap::new(),
+            optimistic: None,
+            rounds: 0,
+            last_round: Instant::now(),
+        }
+    }
+
+    /// Starts tracking a leech connected to the seed, choked until the choker says otherwise
+    ///
+    /// The receiver tells the task serving the leech whether it's choked
+    pub fn register(&mut self, peer_id: PeerId) -> io::Result<watch::Receiver<bool>> {
+        let peer = self.peers.entry(peer_id).or_default();
+        if peer.choked.is_some() {
+            return Err(io::Error::other("Leech is connected already."));
+        }
+
+        let (sender, receiver) = watch::channel(true);
+        peer.choked = Some(sender);
+        Ok(receiver)
+    }
+
+    /// Stops tracking a leech which disconnected, its slot goes to another leech
+    pub fn unregister(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.choked = None;
+            peer.interested = false;
+        }
+        if self.optimistic == Some(*peer_id) {
+            self.optimistic = None;
+        }
+        self.fill_free_slots();
+    }
+
+    /// Interested leeches are unchoked right away while there's a free slot,
+    /// uninterested ones free theirs for another leech
+    pub fn set_interested(&mut self, peer_id: &PeerId, interested: bool) {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return;
+        };
+
+        peer.interested = interested;
+        if !interested {
+            peer.set_choked(true);
+        }
+        self.fill_free_slots();
+    }
+
+    /// Unchokes random interested leeches until all slots are taken, without waiting for a round
+    fn fill_free_slots(&mut self) {
+        for _ in self.unchoked_count()..self.slots {
+            let waiting = self
+                .peers
+                .values()
+                .filter(|peer| peer.choked.is_some() && peer.interested && !peer.is_unchoked())
+                .choose(&mut rand::thread_rng());
+            match waiting {
+                Some(peer) => peer.set_choked(false),
+                None => return,
+            }
+        }
+    }
+
+    pub fn record_uploaded(&mut self, peer_id: &PeerId, bytes: usize) {
+        self.peers.entry(*peer_id).or_default().uploaded += bytes as u64;
+    }
+
+    pub fn record_downloaded(&mut self, peer_id: &PeerId, bytes: usize) {
+        self.peers.entry(*peer_id).or_default().downloaded += bytes as u64;
+    }
+
+    pub fn rates(&self, peer_id: &PeerId) -> Option<PeerRates> {
+        self.peers.get(peer_id).map(|peer| peer.rates)
+    }
+
+    pub fn is_unchoked(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(PeerState::is_unchoked)
+    }
+
+    fn unchoked_count(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.is_unchoked())
+            .count()
+    }
+
+    /// Updates transfer rates and unchokes the interested leeches with the best rates,
+    /// and every few rounds another random interested leech optimistically
+    ///
+    /// Leeches are ranked by how fast they upload to the client, or how fast the client
+    /// uploads to them when `seeding`, as seeds download nothing
+    pub fn run_round(&mut self, seeding: bool) {
+        let elapsed = self.last_round.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.last_round = Instant::now();
+        for peer in self.peers.values_mut() {
+            peer.rates = PeerRates {
+                upload: peer.uploaded as f64 / elapsed,
+                download: peer.downloaded as f64 / elapsed,
+            };
+            peer.uploaded = 0;
+            peer.downloaded = 0;
+        }
+        // Forgets peers which neither are connected nor sent anything lately
+        self.peers
+            .retain(|_, peer| peer.choked.is_some() || peer.rates.download > 0.0);
+
+        let rate = |peer: &PeerState| match seeding {
+            true => peer.rates.upload,
+            false => peer.rates.download,
+        };
+        let mut interested: Vec<(&PeerId, &PeerState)> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.choked.is_some() && peer.interested)
+            .collect();
+        // Unchoked leeches win ties, so that they aren't choked without a reason
+        interested.sort_by(|(_, a), (_, b)| {
+            rate(b)
+                .total_cmp(&rate(a))
+                .then(b.is_unchoked().cmp(&a.is_unchoked()))
+        });
+        let mut unchoked: Vec<PeerId> = interested
+            .iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|(peer_id, _)| **peer_id)
+            .collect();
+
+        // Optimistic unchokes which earned a regular one are replaced early
+        let optimistic_valid = self.optimistic.is_some_and(|optimistic| {
+            !unchoked.contains(&optimistic)
+                && interested
+                    .iter()
+                    .any(|(peer_id, _)| **peer_id == optimistic)
+        });
+        if !optimistic_valid || self.rounds.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS) {
+            self.optimistic = interested
+                .iter()
+                .map(|(peer_id, _)| **peer_id)
+                .filter(|peer_id| !unchoked.contains(peer_id))
+                .choose(&mut rand::thread_rng());
+        }
+        if self.slots > 0 {
+            unchoked.extend(
+                self.optimistic
+                    .filter(|peer_id| !unchoked.contains(peer_id)),
+            );
+        }
+
+        for (peer_id, peer) in &self.peers {
+            peer.set_choked(!unchoked.contains(peer_id));
+        }
+        self.rounds += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    fn peer_id(i: u8) -> PeerId {
+        [i; 20]
+    }
+
+    /// Registers interested leeches 0..count, returning their choke receivers
+    fn interested_leeches(choker: &mut Choker, count: u8) -> Vec<watch::Receiver<bool>> {
+        (0..count)
+            .map(|i| {
+                let choked = choker.register(peer_id(i)).unwrap();
+                choker.set_interested(&peer_id(i), true);
+                choked
+            })
+            .collect()
+    }
+
+    #[test]
+    fn Choker_unchokes_while_slots_are_free() {
+        let mut choker = Choker::new(2);
+        let choked = interested_leeches(&mut choker, 3);
+        assert_eq!(
+            choked.iter().map(|c| *c.borrow()).collect::<Vec<_>>(),
+            [false, false, true]
+        );
+        assert!(choker.register(peer_id(0)).is_err());
+
+        // Uninterested leeches free their slot, disconnected ones too
+        choker.set_interested(&peer_id(0), false);
+        assert!(*choked[0].borrow());
+        assert!(!*choked[2].borrow());
+        choker.set_interested(&peer_id(0), true);
+        assert!(*choked[0].borrow());
+        choker.unregister(&peer_id(1));
+        assert!(!*choked[0].borrow());
+        assert_eq!(choker.unchoked_count(), 2);
+    }
+
+    #[test]
+    fn Choker_unchokes_best_uploaders() {
+        let mut choker = Choker::new(3);
+        interested_leeches(&mut choker, 6);
+        for i in 0..6 {
+            choker.record_downloaded(&peer_id(i), 100 * i as usize);
+        }
+        // Downloads from peers which aren't leeching count too
+        choker.record_downloaded(&peer_id(9), 1000);
+
+        choker.run_round(false);
+        assert!(choker.is_unchoked(&peer_id(5)));
+        assert!(choker.is_unchoked(&peer_id(4)));
+        assert_eq!(choker.unchoked_count(), 3);
+        assert!(choker.rates(&peer_id(9)).unwrap().download > 0.0);
+
+        // The optimistic unchoke is one of the others
+        let optimistic = choker.optimistic.unwrap();
+        assert!(optimistic[0] < 4);
+        assert!(choker.is_unchoked(&optimistic));
+    }
+
+    #[test]
+    fn Choker_ranks_by_upload_when_seeding() {
+        let mut choker = Choker::new(2);
+        interested_leeches(&mut choker, 4);
+        choker.record_uploaded(&peer_id(2), 500);
+        choker.record_downloaded(&peer_id(3), 500);
+
+        choker.run_round(true);
+        assert!(choker.is_unchoked(&peer_id(2)));
+        assert_eq!(choker.unchoked_count(), 2);
+    }
+
+    #[test]
+    fn Choker_rotates_optimistic_unchoke() {
+        let mut choker = Choker::new(1);
+        interested_leeches(&mut choker, 8);
+
+        let mut optimistic = vec![];
+        for _ in 0..30 {
+            choker.run_round(true);
+            assert_eq!(choker.unchoked_count(), 1);
+            optimistic.push(choker.optimistic.unwrap());
+        }
+        // Kept for 3 rounds
+        assert!(optimistic
+            .chunks(3)
+            .all(|chunk| chunk.iter().all(|id| *id == chunk[0])));
+        // 10 rotations among 8 peers, all the same only if rotation is broken
+        assert!(optimistic.iter().any(|id| *id != optimistic[0]));
+    }
+}
@@ -1,217 +1,2323 @@
+use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufStream};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
-use tokio::time;
+use bit_vec::BitVec;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{
+    self, broadcast, oneshot, watch, MappedMutexGuard, MutexGuard, Notify, Semaphore,
+};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
 
-use crate::requests::{LeechRequest, RequestToTracker, SeedResponse, TrackerResponse};
+use crate::choker::{Choker, UNCHOKE_INTERVAL};
+use crate::framing::FrameReader;
+use crate::http_tracker::{self, AnnounceEvent, AnnounceRequest, AnnounceResponse, AnnouncedPeer};
+use crate::metainfo::{InfoHash, Metainfo};
+use crate::peer_wire::{
+    generate_peer_id, max_message_len, BlockRequest, Handshake, PeerId, PeerMessage, BLOCK_LEN,
+    MAX_BLOCK_LEN,
+};
+use crate::piece_picker::{PiecePicker, RarestFirst, Streaming};
+use crate::rate_limit::{RateLimiter, RateLimits};
+use crate::requests::{RequestToTracker, ScrapeStats, TrackerResponse};
+use crate::scheduler::Scheduler;
+use crate::stream_server::StreamServer;
 use crate::torrent_file::TorrentFile;
+use crate::tracker::accept_with_slot;
+use crate::tracker_tiers::TrackerTiers;
+use crate::udp_tracker::UdpTrackerClient;
+
+const DEFAULT_UPLOAD_SLOTS: usize = 4;
+/// Leeches connected at once, choked ones included
+const DEFAULT_MAX_CONNECTIONS: usize = 50;
+/// Connections with nothing received for this long are closed
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_PEERS: usize = 8;
+/// Requests sent to a single peer before waiting for its responses
+const DEFAULT_PIPELINE_DEPTH: usize = 5;
+/// How often the leech looks for new peers, and peers which had nothing to offer are asked again
+const PEER_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// Peers are connected to again this long after failing, twice as long after every further failure
+const PEER_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Peers failing this many times in a row are forgotten until a tracker lists them again
+const MAX_PEER_FAILURES: u32 = 5;
+/// Leeches with more unanswered requests are disconnected
+const MAX_QUEUED_REQUESTS: usize = 256;
+/// JSON tracker responses longer than this are rejected
+const MAX_TRACKER_RESPONSE_LEN: u64 = 1024 * 1024;
+/// Shutdown doesn't wait longer for trackers to acknowledge the client left
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Announces are never repeated sooner, and are retried after this long when no tracker responds
+const DEFAULT_MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Progress is also saved whenever this many more packets were downloaded
+const DEFAULT_SAVE_EVERY_PACKETS: usize = 64;
+/// Completed packets buffered for peer tasks, which check all their packets if they miss some
+const COMPLETED_PACKETS_CAPACITY: usize = 64;
+
 pub struct Client {
     address: SocketAddr,
-    torrent_file: TorrentFile,
+    /// Identifies the torrent's swarm at the tracker, and the torrent in peer handshakes
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    /// Shared with the tasks serving leeches
+    torrent_file: Arc<TorrentFile>,
+    /// Decides which leeches are served, shared with the tasks serving them
+    choker: Arc<Mutex<Choker>>,
+    max_connections: usize,
+    upload_limits: RateLimits,
+    download_limits: RateLimits,
+    idle_timeout: Duration,
+    max_peers: usize,
+    pipeline_depth: usize,
+    piece_picker: Arc<dyn PiecePicker>,
+    /// Set when packets are picked for `stream_loop`'s requests
+    streaming: Option<Arc<Streaming>>,
+    /// UDP trackers by URL, kept to reuse their connection ids
+    udp_trackers: sync::Mutex<HashMap<String, UdpTrackerClient>>,
+    trackers: sync::Mutex<TrackerTiers>,
+    /// Peers listed by the latest tracker response, which the leech connects to
+    swarm_peers: Mutex<HashMap<SocketAddr, SwarmPeer>>,
+    /// Signalled by the leech when it has no peer left, to announce early
+    peers_wanted: Notify,
+    /// Held by the announce loop, so that a client seeding and leeching at once announces once
+    announce_lock: sync::Mutex<()>,
+    /// Set once a tracker acknowledged the "started" announce
+    started: AtomicBool,
+    /// Bytes of blocks sent to leeches, reported to trackers
+    uploaded: Arc<AtomicU64>,
+    /// Bytes of blocks received from peers, reported to trackers
+    downloaded: Arc<AtomicU64>,
+    min_announce_interval: Duration,
+    /// How often the leech saves its progress
+    save_interval: Duration,
+    save_every_packets: usize,
 }
 
 impl Client {
-    pub fn new(address: SocketAddr, torrent_file: TorrentFile) -> Self {
+    pub fn new(address: SocketAddr, info_hash: InfoHash, torrent_file: TorrentFile) -> Self {
         Self {
             address,
-            torrent_file,
+            info_hash,
+            peer_id: generate_peer_id(),
+            torrent_file: Arc::new(torrent_file),
+            choker: Arc::new(Mutex::new(Choker::new(DEFAULT_UPLOAD_SLOTS))),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            upload_limits: RateLimits::default(),
+            download_limits: RateLimits::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_peers: DEFAULT_MAX_PEERS,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            piece_picker: Arc::new(RarestFirst),
+            streaming: None,
+            udp_trackers: sync::Mutex::new(HashMap::new()),
+            trackers: sync::Mutex::new(TrackerTiers::default()),
+            swarm_peers: Mutex::new(HashMap::new()),
+            peers_wanted: Notify::new(),
+            announce_lock: sync::Mutex::new(()),
+            started: AtomicBool::new(false),
+            uploaded: Arc::new(AtomicU64::new(0)),
+            downloaded: Arc::new(AtomicU64::new(0)),
+            min_announce_interval: DEFAULT_MIN_ANNOUNCE_INTERVAL,
+            save_interval: DEFAULT_SAVE_INTERVAL,
+            save_every_packets: DEFAULT_SAVE_EVERY_PACKETS,
         }
     }
 
-    pub async fn request_peerlist(&self, tracker_addr: &SocketAddr) -> io::Result<Vec<SocketAddr>> {
-        let mut stream = TcpStream::connect(tracker_addr).await?;
-        stream
-            .write_all(&serde_json::to_vec(&RequestToTracker::GetPeers)?)
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Handshake the client sends to its peers
+    fn handshake(&self) -> Handshake {
+        Handshake {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+        }
+    }
+
+    /// Limits the number of leeches unchoked at once, others stay connected but choked
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        self.choker = Arc::new(Mutex::new(Choker::new(upload_slots)));
+        self
+    }
+
+    /// Leech connections above `max_connections` wait until one of the served ones closes
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Limits the bytes per second uploaded to this torrent's leeches
+    pub fn with_upload_limit(self, bytes_per_second: u64) -> Self {
+        self.set_upload_limit(Some(bytes_per_second));
+        self
+    }
+
+    /// Limits the bytes per second downloaded from this torrent's peers
+    pub fn with_download_limit(self, bytes_per_second: u64) -> Self {
+        self.set_download_limit(Some(bytes_per_second));
+        self
+    }
+
+    /// Makes transfers also obey limits shared with other clients, e.g. every torrent of a process
+    pub fn with_global_limits(
+        mut self,
+        upload: Arc<RateLimiter>,
+        download: Arc<RateLimiter>,
+    ) -> Self {
+        self.upload_limits.global = Some(upload);
+        self.download_limits.global = Some(download);
+        self
+    }
+
+    /// Changes the upload limit of this torrent, even while seeding, None removes it
+    pub fn set_upload_limit(&self, bytes_per_second: Option<u64>) {
+        self.upload_limits.torrent.set_rate(bytes_per_second);
+    }
+
+    /// Changes the download limit of this torrent, even while leeching, None removes it
+    pub fn set_download_limit(&self, bytes_per_second: Option<u64>) {
+        self.download_limits.torrent.set_rate(bytes_per_second);
+    }
+
+    /// Connections to leeches and seeds which send nothing for longer than `idle_timeout` are closed
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Limits the number of peers downloaded from at once
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    /// Sets how many packets are requested from a single peer before waiting for its responses
+    pub fn with_pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth;
+        self
+    }
+
+    /// Sets the order in which packets are downloaded, rarest first by default
+    pub fn with_piece_picker(mut self, piece_picker: Arc<dyn PiecePicker>) -> Self {
+        self.piece_picker = piece_picker;
+        self.streaming = None;
+        self
+    }
+
+    /// Downloads packets in order, from the ones `stream_loop`'s requests wait for on
+    pub fn with_streaming(mut self) -> Self {
+        let streaming = Arc::new(Streaming::default());
+        self.piece_picker = Arc::clone(&streaming) as Arc<dyn PiecePicker>;
+        self.streaming = Some(streaming);
+        self
+    }
+
+    /// Tracker URLs (`tcp://` for the JSON protocol, `http://` or `udp://`) grouped in tiers
+    /// (BEP 12), the seed and leech loops announce to the first one which responds
+    pub fn with_trackers(mut self, tiers: Vec<Vec<String>>) -> Self {
+        self.trackers = sync::Mutex::new(TrackerTiers::new(tiers));
+        self
+    }
+
+    /// Announces are never repeated sooner, even when trackers ask for a shorter interval
+    pub fn with_min_announce_interval(mut self, min_announce_interval: Duration) -> Self {
+        self.min_announce_interval = min_announce_interval;
+        self
+    }
+
+    /// Sets how often `leech_loop` saves download progress
+    pub fn with_save_interval(mut self, save_interval: Duration) -> Self {
+        self.save_interval = save_interval;
+        self
+    }
+
+    /// Progress is also saved every `save_every_packets` downloaded packets, 0 disables that
+    pub fn with_save_every_packets(mut self, save_every_packets: usize) -> Self {
+        self.save_every_packets = save_every_packets;
+        self
+    }
+
+    /// Creates a client downloading the torrent described by `metainfo` into `path`
+    pub fn from_metainfo(address: SocketAddr, path: &str, metainfo: &Metainfo) -> io::Result<Self> {
+        Ok(Self::new(
+            address,
+            metainfo.info_hash(),
+            TorrentFile::from_metainfo(path, metainfo)?,
+        )
+        .with_trackers(metainfo.announce_list()))
+    }
+
+    /// Like `from_metainfo`, but continues an interrupted download of `metainfo` into `path`
+    /// if it left a progress file behind
+    ///
+    /// With `recheck`, packets the progress file lists as available are verified again
+    /// and downloaded anew if their data doesn't match
+    pub async fn resume(
+        address: SocketAddr,
+        path: &str,
+        metainfo: &Metainfo,
+        recheck: bool,
+    ) -> io::Result<Self> {
+        let torrent_file = TorrentFile::resume(path, metainfo).await?;
+        if recheck {
+            let failed = torrent_file.recheck_available_packets().await?;
+            if failed > 0 {
+                println!("[{address}]: {failed} packets of {path} failed recheck");
+            }
+        }
+
+        Ok(Self::new(address, metainfo.info_hash(), torrent_file)
+            .with_trackers(metainfo.announce_list()))
+    }
+
+    /// Creates a client for the torrent described by `metainfo` whose data may already be
+    /// (partially) at `path`, verifying it with `recheck` first
+    pub async fn from_existing(
+        address: SocketAddr,
+        path: &str,
+        metainfo: &Metainfo,
+    ) -> io::Result<Self> {
+        let client = Self::new(
+            address,
+            metainfo.info_hash(),
+            TorrentFile::from_existing(path, metainfo)?,
+        )
+        .with_trackers(metainfo.announce_list());
+        client.recheck().await?;
+        Ok(client)
+    }
+
+    /// Verifies all data on disk against the packet hashes, rebuilding which packets are
+    /// available and printing progress every 10%
+    ///
+    /// Shouldn't be run during `leech_loop`, as the download wouldn't learn about packets lost.
+    /// Returns the number of available packets
+    pub async fn recheck(&self) -> io::Result<usize> {
+        let packet_count = self.torrent_file.packet_count();
+        let step = packet_count.div_ceil(10).max(1);
+        let available = self
+            .torrent_file
+            .recheck(|checked| {
+                if checked.is_multiple_of(step) || checked == packet_count {
+                    println!(
+                        "[{}]: Rechecked {checked}/{packet_count} packets",
+                        self.address
+                    );
+                }
+            })
+            .await?;
+
+        println!(
+            "[{}]: {available}/{packet_count} packets passed recheck",
+            self.address
+        );
+        Ok(available)
+    }
+
+    /// Sends a single request to `tracker_addr` tracker and reads its response
+    async fn request_tracker(
+        &self,
+        tracker_addr: &SocketAddr,
+        request: &RequestToTracker,
+    ) -> io::Result<TrackerResponse> {
+        let mut responses = self
+            .request_tracker_batch(tracker_addr, std::slice::from_ref(request))
             .await?;
-        stream.write_all("\n".as_bytes()).await?;
+        Ok(responses.remove(0))
+    }
+
+    /// Sends `requests` to `tracker_addr` tracker over a single connection and reads their
+    /// responses, in the same order
+    async fn request_tracker_batch(
+        &self,
+        tracker_addr: &SocketAddr,
+        requests: &[RequestToTracker],
+    ) -> io::Result<Vec<TrackerResponse>> {
+        let mut stream = TcpStream::connect(tracker_addr).await?;
+        let mut batch = vec![];
+        for request in requests {
+            serde_json::to_writer(&mut batch, request)?;
+            batch.push(b'\n');
+        }
+        stream.write_all(&batch).await?;
         stream.flush().await?;
 
-        let mut buf = [0u8; 1024];
-        let bytes_read = stream.read(&mut buf).await?;
-        let response = serde_json::from_slice::<TrackerResponse>(&buf[..bytes_read])?;
+        // Responses are newline-terminated
+        let mut reader = BufReader::new(stream);
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            let mut response = vec![];
+            (&mut reader)
+                .take(MAX_TRACKER_RESPONSE_LEN)
+                .read_until(b'\n', &mut response)
+                .await?;
+            match serde_json::from_slice::<TrackerResponse>(&response)? {
+                TrackerResponse::InvalidRequest => {
+                    return Err(io::Error::other("Sent invalid request."))
+                }
+                response => responses.push(response),
+            }
+        }
+        Ok(responses)
+    }
 
-        match response {
+    pub async fn request_peerlist(&self, tracker_addr: &SocketAddr) -> io::Result<Vec<SocketAddr>> {
+        match self
+            .request_tracker(
+                tracker_addr,
+                &RequestToTracker::GetPeers(self.info_hash, None),
+            )
+            .await?
+        {
             TrackerResponse::Peers(peers) => Ok(peers),
-            TrackerResponse::InvalidRequest => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Sent invalid request.",
-            )),
-            _ => {
-                unreachable!()
-            }
+            _ => Err(io::Error::other("Unexpected tracker response.")),
         }
     }
 
     /// Registers as a peer at `tracker_addr` tracker
-    pub async fn register_as_peer(&self, tracker_addr: &SocketAddr) -> io::Result<()> {
-        let mut stream = TcpStream::connect(tracker_addr).await?;
-        stream
-            .write_all(&serde_json::to_vec(&RequestToTracker::RegisterAsPeer(
-                self.address,
-            ))?)
-            .await?;
-        stream.write_all("\n".as_bytes()).await?;
-        stream.flush().await?;
+    ///
+    /// Returns the interval after which the tracker expects the client to register again
+    pub async fn register_as_peer(&self, tracker_addr: &SocketAddr) -> io::Result<Duration> {
+        let request = self.register_request().await;
+        match self.request_tracker(tracker_addr, &request).await? {
+            TrackerResponse::RegisteredSuccesfully(interval) => Ok(interval),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
 
-        Ok(())
+    async fn register_request(&self) -> RequestToTracker {
+        RequestToTracker::RegisterAsPeer(
+            self.info_hash,
+            self.address,
+            self.torrent_file.bytes_left().await as u64,
+            self.uploaded.load(Ordering::Relaxed),
+            self.downloaded.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Tells `tracker_addr` tracker that the client finished downloading
+    pub async fn report_completed(&self, tracker_addr: &SocketAddr) -> io::Result<()> {
+        let request = RequestToTracker::ReportCompleted(self.info_hash, self.address);
+        match self.request_tracker(tracker_addr, &request).await? {
+            TrackerResponse::CompletionRecorded => Ok(()),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Asks `tracker_addr` tracker for the stats of the torrent's swarm
+    pub async fn request_stats(&self, tracker_addr: &SocketAddr) -> io::Result<ScrapeStats> {
+        let request = RequestToTracker::Scrape(vec![self.info_hash]);
+        match self.request_tracker(tracker_addr, &request).await? {
+            TrackerResponse::Stats(stats) if stats.len() == 1 => Ok(stats[0]),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Tells `tracker_addr` tracker that the client stopped seeding
+    pub async fn deregister(&self, tracker_addr: &SocketAddr) -> io::Result<()> {
+        let request = RequestToTracker::Deregister(self.info_hash, self.address);
+        match self.request_tracker(tracker_addr, &request).await? {
+            TrackerResponse::Deregistered => Ok(()),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Announces the client at the JSON (`tcp://`), HTTP or UDP tracker at `url`, returning the
+    /// swarm's other peers
+    pub async fn announce(
+        &self,
+        url: &str,
+        event: Option<AnnounceEvent>,
+    ) -> io::Result<AnnounceResponse> {
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            ip: Some(self.address.ip()).filter(|ip| !ip.is_unspecified()),
+            port: self.address.port(),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.torrent_file.bytes_left().await as u64,
+            event,
+            numwant: None,
+            compact: true,
+        };
+
+        if let Some(tracker_addr) = url.strip_prefix("tcp://") {
+            let tracker_addr = lookup_host(tracker_addr).await?.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid tracker URL: {url}."),
+                )
+            })?;
+            return self.announce_json(&tracker_addr, event).await;
+        }
+        if url.starts_with("udp://") {
+            return self.udp_tracker(url).await?.announce(&request).await;
+        }
+        http_tracker::announce(url, &request).await
+    }
+
+    /// `announce` for trackers speaking the JSON protocol, which takes a request per step,
+    /// all sent over a single connection
+    async fn announce_json(
+        &self,
+        tracker_addr: &SocketAddr,
+        event: Option<AnnounceEvent>,
+    ) -> io::Result<AnnounceResponse> {
+        let mut requests = vec![];
+        match event {
+            Some(AnnounceEvent::Stopped) => {
+                self.deregister(tracker_addr).await?;
+                // Nothing is expected back by a peer leaving the swarm
+                return Ok(AnnounceResponse {
+                    interval: Duration::ZERO,
+                    seeders: 0,
+                    leechers: 0,
+                    peers: vec![],
+                });
+            }
+            Some(AnnounceEvent::Completed) => {
+                requests.push(RequestToTracker::ReportCompleted(
+                    self.info_hash,
+                    self.address,
+                ));
+            }
+            _ => {}
+        }
+        requests.extend([
+            self.register_request().await,
+            RequestToTracker::GetPeers(self.info_hash, None),
+            RequestToTracker::Scrape(vec![self.info_hash]),
+        ]);
+
+        let responses = self.request_tracker_batch(tracker_addr, &requests).await?;
+
+        use TrackerResponse::{Peers, RegisteredSuccesfully, Stats};
+        let (interval, peers, stats) = match &responses[..] {
+            [.., RegisteredSuccesfully(interval), Peers(peers), Stats(stats)]
+                if stats.len() == 1 =>
+            {
+                (*interval, peers, stats[0])
+            }
+            _ => return Err(io::Error::other("Unexpected tracker response.")),
+        };
+
+        Ok(AnnounceResponse {
+            interval,
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            // Unlike announce responses, JSON peer lists include the requesting peer
+            peers: peers
+                .iter()
+                .filter(|peer| **peer != self.address)
+                .map(|peer| AnnouncedPeer::from(*peer))
+                .collect(),
+        })
+    }
+
+    /// Announces to the client's trackers, trying them in tier order until one responds
+    ///
+    /// Peers listed in the response are added to those the leech connects to
+    pub async fn announce_to_trackers(
+        &self,
+        event: Option<AnnounceEvent>,
+    ) -> io::Result<AnnounceResponse> {
+        let urls = self.trackers.lock().await.urls();
+        let mut last_err = io::Error::other("No trackers to announce to.");
+        for url in urls {
+            match self.announce(&url, event).await {
+                Ok(response) => {
+                    self.trackers.lock().await.promote(&url);
+                    // Nothing is listed back to a peer leaving the swarm
+                    if event != Some(AnnounceEvent::Stopped) {
+                        self.update_swarm_peers(&response.peers);
+                    }
+                    return Ok(response);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Replaces the peers the leech connects to with `peers`, keeping the failures of those
+    /// listed before
+    fn update_swarm_peers(&self, peers: &[AnnouncedPeer]) {
+        let mut swarm_peers = self.swarm_peers.lock().unwrap();
+        let listed = peers
+            .iter()
+            .map(|peer| {
+                let known = swarm_peers.remove(&peer.address);
+                (peer.address, known.unwrap_or_default())
+            })
+            .collect();
+        *swarm_peers = listed;
+    }
+
+    /// Makes the leech wait before connecting to a peer whose download task failed, and forget
+    /// the peer after `MAX_PEER_FAILURES` failures in a row
+    fn peer_failed(&self, peer_addr: SocketAddr) {
+        let mut swarm_peers = self.swarm_peers.lock().unwrap();
+        let Some(peer) = swarm_peers.get_mut(&peer_addr) else {
+            return;
+        };
+        peer.failures += 1;
+        if peer.failures >= MAX_PEER_FAILURES {
+            swarm_peers.remove(&peer_addr);
+            return;
+        }
+        peer.retry_at = Some(Instant::now() + PEER_RETRY_BACKOFF * 2u32.pow(peer.failures - 1));
+    }
+
+    /// Announces to the client's trackers at the interval they ask for, or sooner when the leech
+    /// runs out of peers, retrying after `min_announce_interval` when none of them responds
+    ///
+    /// Only one such loop runs per client at a time, others wait for it to stop
+    async fn do_announce_loop(&self) -> io::Result<()> {
+        if self.trackers.lock().await.is_empty() {
+            return std::future::pending().await;
+        }
+        let _lock = self.announce_lock.lock().await;
+
+        loop {
+            let event = match self.started.load(Ordering::Relaxed) {
+                true => None,
+                false => Some(AnnounceEvent::Started),
+            };
+            let last_announce = Instant::now();
+            let interval = match self.announce_to_trackers(event).await {
+                Ok(response) => {
+                    self.started.store(true, Ordering::Relaxed);
+                    response.interval.max(self.min_announce_interval)
+                }
+                Err(err) => {
+                    println!("[{}]: No tracker responded: {err}", self.address);
+                    self.min_announce_interval
+                }
+            };
+
+            select! {
+                _ = time::sleep_until(last_announce + interval) => {}
+                _ = self.peers_wanted.notified() => {
+                    time::sleep_until(last_announce + self.min_announce_interval).await
+                }
+            }
+        }
+    }
+
+    /// Asks the HTTP or UDP tracker at `url` for the stats of the torrent's swarm
+    pub async fn scrape(&self, url: &str) -> io::Result<ScrapeStats> {
+        let stats = match url.starts_with("udp://") {
+            true => {
+                self.udp_tracker(url)
+                    .await?
+                    .scrape(&[self.info_hash])
+                    .await?
+            }
+            false => http_tracker::scrape(url, &[self.info_hash]).await?,
+        };
+        stats
+            .first()
+            .copied()
+            .ok_or_else(|| io::Error::other("Unexpected tracker response."))
+    }
+
+    /// Client of the UDP tracker at `url`, kept to reuse its connection id
+    async fn udp_tracker(&self, url: &str) -> io::Result<MappedMutexGuard<'_, UdpTrackerClient>> {
+        let mut udp_trackers = self.udp_trackers.lock().await;
+        if let Entry::Vacant(entry) = udp_trackers.entry(url.to_owned()) {
+            let tracker_addr = url.trim_start_matches("udp://").split('/').next().unwrap();
+            entry.insert(UdpTrackerClient::new(tracker_addr).await?);
+        }
+        Ok(MutexGuard::map(udp_trackers, |udp_trackers| {
+            udp_trackers.get_mut(url).unwrap()
+        }))
     }
 
     /// Launches the seed loop, which stops when a message is passed through `shutdown_channel`
+    ///
+    /// The client announces itself to its trackers meanwhile, and tells them it left on shutdown,
+    /// waiting at most `STOPPED_ANNOUNCE_TIMEOUT` for them
     pub async fn seed_loop(&self, shutdown_channel: oneshot::Receiver<()>) -> io::Result<()> {
         tokio::select! {
                 err = self.do_seed_loop() => err,
+                err = self.do_announce_loop() => err,
                 _ = shutdown_channel => {
                     println!("Shutting down");
-                    Ok(())
+                    self.announce_stopped().await
             }
         }
     }
 
-    /// Actual `seed_loop` body
+    /// Tells the trackers the client left, if they were told it started, waiting at most
+    /// `STOPPED_ANNOUNCE_TIMEOUT` for them
+    async fn announce_stopped(&self) -> io::Result<()> {
+        if !self.started.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let stopped = self.announce_to_trackers(Some(AnnounceEvent::Stopped));
+        match time::timeout(STOPPED_ANNOUNCE_TIMEOUT, stopped).await {
+            Ok(res) => res.map(|_| ()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No tracker acknowledged the client left.",
+            )),
+        }
+    }
+
+    /// Actual `seed_loop` body, serves every leech in its own task
+    ///
+    /// Runs a choking round every `UNCHOKE_INTERVAL` meanwhile
     async fn do_seed_loop(&self) -> io::Result<()> {
         let listener = TcpListener::bind(self.address).await?;
-        let mut packet_buffer = [0u8; 1024];
+        let connection_slots = Arc::new(Semaphore::new(self.max_connections));
+        let mut leeches = JoinSet::new();
+        let mut choke_rounds = time::interval(UNCHOKE_INTERVAL);
 
-        while let Ok((mut stream, _)) = listener.accept().await {
-            while let Ok(bytes_read) = stream.read(&mut packet_buffer).await {
-                if bytes_read == 0 {
-                    break;
+        loop {
+            let (stream, slot) = select! {
+                res = accept_with_slot(&listener, &connection_slots) => res?,
+                // Reaps finished connections
+                Some(_) = leeches.join_next() => continue,
+                _ = choke_rounds.tick() => {
+                    let seeding = self.torrent_file.bytes_left().await == 0;
+                    self.choker.lock().unwrap().run_round(seeding);
+                    continue;
                 }
+            };
 
-                match serde_json::from_slice::<LeechRequest>(&packet_buffer[..bytes_read]) {
-                    Ok(LeechRequest::GetAvailability) => {
-                        stream
-                            .write_all(&serde_json::to_vec(&SeedResponse::Availability(
-                                self.torrent_file.read_packet_availability().await,
-                            ))?)
-                            .await?;
-                    }
-                    Ok(LeechRequest::GetPackets(start, count)) => {
-                        let data = self.torrent_file.read_packets(start, count).await?;
-                        stream.write_all(&data).await?;
-                    }
-                    _ => {
-                        stream
-                            .write_all(&serde_json::to_vec(&SeedResponse::InvalidRequest)?)
-                            .await?;
-                    }
-                };
-
-                stream.flush().await?;
-            }
+            let torrent_file = Arc::clone(&self.torrent_file);
+            let handshake = self.handshake();
+            let idle_timeout = self.idle_timeout;
+            let choker = Arc::clone(&self.choker);
+            let upload_limits = self.upload_limits.clone();
+            let uploaded = Arc::clone(&self.uploaded);
+            leeches.spawn(async move {
+                let _slot = slot;
+                serve_leech(
+                    stream,
+                    torrent_file,
+                    handshake,
+                    idle_timeout,
+                    choker,
+                    upload_limits,
+                    uploaded,
+                )
+                .await
+            });
         }
-        Ok(())
     }
 
-    /// Launches the leech loop, which stops when a message is passed through `shutdown_channel`
-    pub async fn leech_loop(
+    /// Serves the torrent's files over HTTP at `address` while they're downloaded, with Range
+    /// support, until a message is passed through `shutdown_channel`
+    ///
+    /// Requests wait for the packets they need, see `with_streaming` to download those first
+    pub async fn stream_loop(
         &self,
-        tracker_addr: &SocketAddr,
+        address: SocketAddr,
         shutdown_channel: oneshot::Receiver<()>,
     ) -> io::Result<()> {
+        let mut server = StreamServer::new(Arc::clone(&self.torrent_file));
+        if let Some(streaming) = &self.streaming {
+            server = server.with_picker(Arc::clone(streaming));
+        }
+        server.listen(&address, shutdown_channel).await
+    }
+
+    /// Launches the leech loop, which stops when a message is passed through `shutdown_channel`
+    ///
+    /// The client announces itself to its trackers meanwhile, to learn the swarm's peers, and
+    /// tells them it left on shutdown like `seed_loop` does
+    ///
+    /// Progress is saved periodically, every `save_every_packets` packets and on shutdown
+    pub async fn leech_loop(&self, shutdown_channel: oneshot::Receiver<()>) -> io::Result<()> {
         tokio::select! {
-            _ = shutdown_channel => { self.save_progress().await },
-            res = self.do_leech_loop(tracker_addr) => { res },
+            _ = shutdown_channel => {
+                let saved = self.save_progress().await;
+                let stopped = self.announce_stopped().await;
+                saved.and(stopped)
+            },
+            res = self.do_leech_loop() => { res },
+            res = self.do_announce_loop() => { res },
+            res = self.save_progress_periodically() => { res },
+        }
+    }
+
+    /// Saves download progress every `save_interval`, never returning
+    async fn save_progress_periodically(&self) -> io::Result<()> {
+        let mut interval = time::interval(self.save_interval);
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.save_progress().await {
+                println!("[{}]: Couldn't save progress: {err}", self.address);
+            }
         }
     }
 
     /// Actual `leech_loop` body
-    async fn do_leech_loop(&self, tracker_addr: &SocketAddr) -> io::Result<()> {
-        let mut buf = vec![0u8; self.torrent_file.packet_size()];
-        let packet_availability = self.torrent_file.read_packet_availability().await;
+    ///
+    /// Downloads from up to `max_peers` peers at once, each in its own task, connecting to peers
+    /// learned from trackers whenever there's a free spot
+    ///
+    /// The finished download is then saved and reported to the trackers
+    async fn do_leech_loop(&self) -> io::Result<()> {
+        let download = Arc::new(self.start_download().await);
+        let was_done = download.is_done();
+        // Peers with a running download task
+        let mut peers = HashSet::new();
+        let mut peer_tasks = JoinSet::new();
 
-        for (i, _) in packet_availability
-            .iter()
-            .enumerate()
-            .filter(|(_, available)| !available)
-        {
-            let mut stream = self.peer_stream_with_packet(i, tracker_addr).await?;
+        while !download.is_done() {
+            if peers.len() < self.max_peers {
+                let now = Instant::now();
+                let swarm_peers: Vec<_> = self
+                    .swarm_peers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, peer)| peer.retry_at.is_none_or(|retry_at| retry_at <= now))
+                    .map(|(address, _)| *address)
+                    .collect();
+                for peer_addr in swarm_peers {
+                    if peers.len() >= self.max_peers {
+                        break;
+                    }
+                    if peer_addr == self.address
+                        || peers.contains(&peer_addr)
+                        || download.bad_peers.lock().unwrap().contains(&peer_addr)
+                    {
+                        continue;
+                    }
 
-            stream
-                .write_all(&serde_json::to_vec(&LeechRequest::GetPackets(i, 1))?)
-                .await?;
+                    peers.insert(peer_addr);
+                    let download = Arc::clone(&download);
+                    peer_tasks.spawn(async move {
+                        (peer_addr, download.download_from_peer(peer_addr).await)
+                    });
+                }
+                if peers.is_empty() {
+                    self.peers_wanted.notify_one();
+                }
+            }
 
-            // Packets are read raw (instead of having their own serializable enum entry) to save bandwidth
-            let bytes_read = stream.read(&mut buf).await?;
-            println!(
-                "[{}]: Packet {i} - got {} bytes from {}",
-                self.address,
-                bytes_read,
-                stream.peer_addr()?
-            );
+            // Waits for a peer to finish, or a while before looking for new peers
+            select! {
+                Some(res) = peer_tasks.join_next() => {
+                    let (peer_addr, res) = res.map_err(io::Error::other)?;
+                    if let Err(err) = res {
+                        println!("[{}]: Dropped peer {peer_addr}: {err}", self.address);
+                        self.peer_failed(peer_addr);
+                    }
+                    peers.remove(&peer_addr);
+                }
+                _ = time::sleep(PEER_REFRESH_INTERVAL) => {}
+            }
+        }
 
-            self.torrent_file
-                .write_packets(i, &buf[..bytes_read])
-                .await?;
+        // Downloads resumed after they were already complete aren't counted again
+        if !was_done {
+            self.save_progress().await?;
+            if let Err(err) = self
+                .announce_to_trackers(Some(AnnounceEvent::Completed))
+                .await
+            {
+                println!(
+                    "[{}]: Couldn't report finished download: {err}",
+                    self.address
+                );
+            }
         }
         Ok(())
     }
 
+    async fn start_download(&self) -> Download {
+        Download {
+            address: self.address,
+            handshake: self.handshake(),
+            torrent_file: Arc::clone(&self.torrent_file),
+            scheduler: Mutex::new(
+                Scheduler::new(self.torrent_file.read_packet_availability().await)
+                    .with_picker(Arc::clone(&self.piece_picker)),
+            ),
+            bad_peers: Mutex::new(HashSet::new()),
+            pipeline_depth: self.pipeline_depth,
+            idle_timeout: self.idle_timeout,
+            save_every_packets: self.save_every_packets,
+            packets_written: AtomicUsize::new(0),
+            completed: broadcast::channel(COMPLETED_PACKETS_CAPACITY).0,
+            choker: Arc::clone(&self.choker),
+            download_limits: self.download_limits.clone(),
+            downloaded: Arc::clone(&self.downloaded),
+        }
+    }
+
     pub async fn save_progress(&self) -> io::Result<()> {
         self.torrent_file.save_progress_to_file().await
     }
+}
+
+#[derive(Default)]
+/// Peer learned from a tracker
+struct SwarmPeer {
+    /// Download tasks which failed in a row
+    failures: u32,
+    /// The leech doesn't connect to the peer again before then
+    retry_at: Option<Instant>,
+}
+
+/// State of a download shared by the tasks downloading from individual peers
+struct Download {
+    /// Address of the downloading client, for logging
+    address: SocketAddr,
+    handshake: Handshake,
+    torrent_file: Arc<TorrentFile>,
+    scheduler: Mutex<Scheduler>,
+    /// Peers which sent packets that failed hash verification
+    bad_peers: Mutex<HashSet<SocketAddr>>,
+    pipeline_depth: usize,
+    idle_timeout: Duration,
+    save_every_packets: usize,
+    packets_written: AtomicUsize,
+    /// Packets just written, for the endgame's other peer tasks to cancel their requests
+    completed: broadcast::Sender<usize>,
+    /// Learns how fast peers upload to the client, to reciprocate
+    choker: Arc<Mutex<Choker>>,
+    download_limits: RateLimits,
+    /// The client's total of downloaded bytes
+    downloaded: Arc<AtomicU64>,
+}
+
+/// Packet whose blocks are being downloaded
+struct PendingPacket {
+    data: Vec<u8>,
+    /// Received blocks, `BLOCK_LEN` bytes each (but the last one)
+    received: BitVec,
+}
+
+impl PendingPacket {
+    fn new(len: usize) -> Self {
+        Self {
+            data: vec![0; len],
+            received: BitVec::from_elem(len.div_ceil(BLOCK_LEN), false),
+        }
+    }
 
-    /// Loops until it finds a peer with `packet_index` packet available and returns a TcpStream to that peer
-    async fn peer_stream_with_packet(
+    /// Block requests covering the whole packet
+    fn requests(&self, index: usize) -> impl Iterator<Item = BlockRequest> + '_ {
+        (0..self.data.len())
+            .step_by(BLOCK_LEN)
+            .map(move |begin| BlockRequest {
+                index,
+                begin,
+                length: min(BLOCK_LEN, self.data.len() - begin),
+            })
+    }
+
+    /// Returns false if `block` doesn't answer any of the packet's block requests
+    fn add_block(&mut self, begin: usize, block: &[u8]) -> bool {
+        if !begin.is_multiple_of(BLOCK_LEN)
+            || begin >= self.data.len()
+            || block.len() != min(BLOCK_LEN, self.data.len() - begin)
+        {
+            return false;
+        }
+
+        self.data[begin..begin + block.len()].copy_from_slice(block);
+        self.received.set(begin / BLOCK_LEN, true);
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.all()
+    }
+}
+
+impl Download {
+    fn is_done(&self) -> bool {
+        self.scheduler.lock().unwrap().is_done()
+    }
+
+    /// Downloads packets from `peer_addr` until all packets are downloaded or the peer fails
+    ///
+    /// Packets requested from the peer but not received are returned to the scheduler,
+    /// and the peer's packets stop counting towards their replicas
+    async fn download_from_peer(&self, peer_addr: SocketAddr) -> io::Result<()> {
+        let mut pending = HashMap::new();
+        let mut availability = BitVec::from_elem(self.torrent_file.packet_count(), false);
+        let res = self
+            .do_download_from_peer(peer_addr, &mut pending, &mut availability)
+            .await;
+
+        let mut scheduler = self.scheduler.lock().unwrap();
+        for packet_index in pending.into_keys() {
+            scheduler.release(packet_index);
+        }
+        scheduler.remove_peer(&availability);
+        res
+    }
+
+    async fn do_download_from_peer(
         &self,
+        peer_addr: SocketAddr,
+        pending: &mut HashMap<usize, PendingPacket>,
+        availability: &mut BitVec,
+    ) -> io::Result<()> {
+        let mut stream = TcpStream::connect(peer_addr).await?;
+        self.handshake.write_to(&mut stream).await?;
+        let peer_handshake =
+            time::timeout(self.idle_timeout, Handshake::read_from(&mut stream)).await??;
+        if peer_handshake.info_hash != self.handshake.info_hash {
+            return Err(io::Error::other("Peer serves a different torrent."));
+        }
+        if peer_handshake.peer_id == self.handshake.peer_id {
+            return Err(io::Error::other("Connected to itself."));
+        }
+
+        let (reader, mut writer) = stream.split();
+        let packet_count = self.torrent_file.packet_count();
+        let mut frames = FrameReader::new(reader, max_message_len(packet_count));
+        let mut choked = true;
+        let mut interested = false;
+        let mut completed = self.completed.subscribe();
+        let mut keep_alive = keep_alive_interval(self.idle_timeout);
+        let idle = time::sleep(self.idle_timeout);
+        tokio::pin!(idle);
+
+        loop {
+            let is_interesting = self.scheduler.lock().unwrap().is_interesting(availability);
+            if is_interesting != interested {
+                interested = is_interesting;
+                let message = match interested {
+                    true => PeerMessage::Interested,
+                    false => PeerMessage::NotInterested,
+                };
+                message.write_to(&mut writer).await?;
+                keep_alive.reset();
+            }
+
+            if !choked {
+                // Keeps up to `pipeline_depth` packets requested
+                let count = self.pipeline_depth.saturating_sub(pending.len());
+                let assigned = {
+                    let mut scheduler = self.scheduler.lock().unwrap();
+                    let assigned = scheduler.assign(availability, count);
+                    // Packets left are requested from several peers, the first copy wins
+                    match assigned.is_empty() && scheduler.is_endgame() {
+                        true => scheduler
+                            .assign_endgame(availability, count, |i| pending.contains_key(&i)),
+                        false => assigned,
+                    }
+                };
+
+                for packet_index in assigned {
+                    let packet = PendingPacket::new(self.torrent_file.packet_len(packet_index));
+                    for request in packet.requests(packet_index) {
+                        PeerMessage::Request(request).write_to(&mut writer).await?;
+                    }
+                    pending.insert(packet_index, packet);
+                    keep_alive.reset();
+                }
+            }
+
+            if pending.is_empty() && self.is_done() {
+                return Ok(());
+            }
+
+            let message = select! {
+                message = PeerMessage::read_from(&mut frames) => {
+                    idle.as_mut().reset(Instant::now() + self.idle_timeout);
+                    message?.ok_or(io::ErrorKind::UnexpectedEof)?
+                }
+                _ = &mut idle => return Err(io::ErrorKind::TimedOut.into()),
+                // Whether a packet was missed or not, all pending packets are checked
+                _ = completed.recv() => {
+                    self.cancel_downloaded(pending, &mut writer).await?;
+                    continue;
+                }
+                _ = keep_alive.tick() => {
+                    PeerMessage::KeepAlive.write_to(&mut writer).await?;
+                    continue;
+                }
+            };
+            match message {
+                PeerMessage::Choke => {
+                    choked = true;
+                    // The peer drops requests it hasn't answered yet
+                    let mut scheduler = self.scheduler.lock().unwrap();
+                    for (packet_index, _) in pending.drain() {
+                        scheduler.release(packet_index);
+                    }
+                }
+                PeerMessage::Unchoke => choked = false,
+                PeerMessage::Have(index) if index < packet_count && !availability[index] => {
+                    availability.set(index, true);
+                    self.scheduler.lock().unwrap().add_replica(index);
+                }
+                PeerMessage::Bitfield(mut bitfield) => {
+                    if bitfield.len() != packet_count.div_ceil(8) * 8
+                        || bitfield.iter().skip(packet_count).any(|bit| bit)
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Invalid bitfield.",
+                        ));
+                    }
+                    bitfield.truncate(packet_count);
+                    let mut scheduler = self.scheduler.lock().unwrap();
+                    scheduler.remove_peer(availability);
+                    scheduler.add_peer(&bitfield);
+                    *availability = bitfield;
+                }
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    // Pieces of requests dropped after a choke may still arrive
+                    let Some(packet) = pending.get_mut(&index) else {
+                        continue;
+                    };
+                    if !packet.add_block(begin, &block) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unrequested block.",
+                        ));
+                    }
+                    self.choker
+                        .lock()
+                        .unwrap()
+                        .record_downloaded(&peer_handshake.peer_id, block.len());
+                    self.downloaded
+                        .fetch_add(block.len() as u64, Ordering::Relaxed);
+                    // Holds off reading further blocks, so that the peer's sending slows down
+                    self.download_limits.acquire(block.len()).await;
+                    if packet.is_complete() {
+                        let packet = pending.remove(&index).unwrap();
+                        self.write_packet(peer_addr, index, &packet.data).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Cancels requests of pending packets which were downloaded from other peers
+    async fn cancel_downloaded<W: AsyncWrite + Unpin>(
+        &self,
+        pending: &mut HashMap<usize, PendingPacket>,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let downloaded: Vec<usize> = {
+            let scheduler = self.scheduler.lock().unwrap();
+            pending
+                .keys()
+                .copied()
+                .filter(|&i| scheduler.is_downloaded(i))
+                .collect()
+        };
+
+        for packet_index in downloaded {
+            let packet = pending.remove(&packet_index).unwrap();
+            let requests: Vec<_> = packet.requests(packet_index).collect();
+            for request in requests {
+                if !packet.received[request.begin / BLOCK_LEN] {
+                    PeerMessage::Cancel(request).write_to(writer).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a packet received from `peer_addr`, marking the peer bad if it's corrupt
+    ///
+    /// Packets downloaded from another peer meanwhile are dropped
+    async fn write_packet(
+        &self,
+        peer_addr: SocketAddr,
         packet_index: usize,
-        tracker_addr: &SocketAddr,
-    ) -> io::Result<TcpStream> {
-        let peerlist = {
-            let mut peerlist = self.request_peerlist(tracker_addr).await?;
+        data: &[u8],
+    ) -> io::Result<()> {
+        println!(
+            "[{}]: Packet {packet_index} - got {} bytes from {}",
+            self.address,
+            data.len(),
+            peer_addr
+        );
+
+        if self.scheduler.lock().unwrap().is_downloaded(packet_index) {
+            return Ok(());
+        }
+
+        match self.torrent_file.write_packets(packet_index, data).await {
+            Ok(()) => {
+                if !self.scheduler.lock().unwrap().complete(packet_index) {
+                    return Ok(());
+                }
+                // No task may be listening
+                let _ = self.completed.send(packet_index);
+                let written = self.packets_written.fetch_add(1, Ordering::Relaxed) + 1;
+                if written.is_multiple_of(self.save_every_packets) {
+                    if let Err(err) = self.torrent_file.save_progress_to_file().await {
+                        println!("[{}]: Couldn't save progress: {err}", self.address);
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.scheduler.lock().unwrap().release(packet_index);
+                if err.kind() == io::ErrorKind::InvalidData {
+                    println!(
+                        "[{}]: Packet {packet_index} from {} is corrupt, re-requesting",
+                        self.address, peer_addr
+                    );
+                    self.bad_peers.lock().unwrap().insert(peer_addr);
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Uploads to a single leech until it disconnects or stays idle for `idle_timeout`
+///
+/// The leech is served only while `choker` keeps it unchoked, as fast as `upload_limits` allow.
+/// Sent blocks are added to `uploaded`
+async fn serve_leech(
+    mut stream: TcpStream,
+    torrent_file: Arc<TorrentFile>,
+    handshake: Handshake,
+    idle_timeout: Duration,
+    choker: Arc<Mutex<Choker>>,
+    upload_limits: RateLimits,
+    uploaded: Arc<AtomicU64>,
+) -> io::Result<()> {
+    let peer_handshake = time::timeout(idle_timeout, Handshake::read_from(&mut stream)).await??;
+    if peer_handshake.info_hash != handshake.info_hash {
+        return Err(io::Error::other("Leech requested an unknown torrent."));
+    }
+    handshake.write_to(&mut stream).await?;
+
+    let peer_id = peer_handshake.peer_id;
+    let choke = choker.lock().unwrap().register(peer_id)?;
+    let res = upload_to_leech(
+        stream,
+        &torrent_file,
+        peer_id,
+        idle_timeout,
+        &choker,
+        choke,
+        &upload_limits,
+        &uploaded,
+    )
+    .await;
+    choker.lock().unwrap().unregister(&peer_id);
+    res
+}
+
+/// `serve_leech` body once the leech is registered with `choker`
+#[allow(clippy::too_many_arguments)]
+async fn upload_to_leech(
+    mut stream: TcpStream,
+    torrent_file: &TorrentFile,
+    peer_id: PeerId,
+    idle_timeout: Duration,
+    choker: &Mutex<Choker>,
+    mut choke: watch::Receiver<bool>,
+    upload_limits: &RateLimits,
+    uploaded: &AtomicU64,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut frames = FrameReader::new(reader, max_message_len(torrent_file.packet_count()));
+
+    // Packets the leech knows the client has
+    let mut announced = torrent_file.read_packet_availability().await;
+    if announced.any() {
+        PeerMessage::Bitfield(announced.clone())
+            .write_to(&mut writer)
+            .await?;
+    }
+
+    let mut choked = true;
+    let mut requests = VecDeque::new();
+    let mut refresh = time::interval(PEER_REFRESH_INTERVAL);
+    let mut keep_alive = keep_alive_interval(idle_timeout);
+    let idle = time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    loop {
+        select! {
+            // Incoming messages go first, so cancels arrive before their requests are served
+            biased;
 
-            while peerlist.is_empty() {
-                time::sleep(Duration::from_millis(50)).await;
-                peerlist = self.request_peerlist(tracker_addr).await?;
+            message = PeerMessage::read_from(&mut frames) => {
+                let Some(message) = message? else {
+                    return Ok(());
+                };
+                idle.as_mut().reset(Instant::now() + idle_timeout);
+
+                match message {
+                    PeerMessage::Interested => choker.lock().unwrap().set_interested(&peer_id, true),
+                    PeerMessage::NotInterested => {
+                        choker.lock().unwrap().set_interested(&peer_id, false)
+                    }
+                    // Requests of choked leeches are dropped
+                    PeerMessage::Request(request) if !choked => {
+                        if !is_request_valid(torrent_file, &announced, &request) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid block request.",
+                            ));
+                        }
+                        if requests.len() >= MAX_QUEUED_REQUESTS {
+                            return Err(io::Error::other("Too many queued requests."));
+                        }
+                        requests.push_back(request);
+                    }
+                    PeerMessage::Cancel(request) => requests.retain(|queued| *queued != request),
+                    _ => {}
+                }
+            }
+            _ = &mut idle => return Ok(()),
+            res = choke.changed() => {
+                res.map_err(io::Error::other)?;
+                if *choke.borrow_and_update() != choked {
+                    choked = !choked;
+                    let message = match choked {
+                        true => PeerMessage::Choke,
+                        false => PeerMessage::Unchoke,
+                    };
+                    message.write_to(&mut writer).await?;
+                    keep_alive.reset();
+                    // Choked leeches know their requests won't be answered
+                    if choked {
+                        requests.clear();
+                    }
+                }
+            }
+            // Tells the leech about packets downloaded in the meantime
+            _ = refresh.tick() => {
+                let availability = torrent_file.read_packet_availability().await;
+                for index in (0..availability.len()).filter(|&i| availability[i] && !announced[i]) {
+                    PeerMessage::Have(index).write_to(&mut writer).await?;
+                    keep_alive.reset();
+                }
+                announced = availability;
+            }
+            _ = async {}, if !requests.is_empty() => {
+                let request = requests.pop_front().unwrap();
+                upload_limits.acquire(request.length).await;
+                let block = torrent_file
+                    .read_block(request.index, request.begin, request.length)
+                    .await?;
+                PeerMessage::Piece {
+                    index: request.index,
+                    begin: request.begin,
+                    block,
+                }
+                .write_to(&mut writer)
+                .await?;
+                keep_alive.reset();
+                choker.lock().unwrap().record_uploaded(&peer_id, request.length);
+                uploaded.fetch_add(request.length as u64, Ordering::Relaxed);
             }
-            peerlist
+            _ = keep_alive.tick() => PeerMessage::KeepAlive.write_to(&mut writer).await?,
+        }
+    }
+}
+
+/// Ticks once a connection has sent nothing for half of `idle_timeout`, so that a keep-alive
+/// stops the peer from closing it, and should be reset whenever another message is sent
+fn keep_alive_interval(idle_timeout: Duration) -> time::Interval {
+    let period = (idle_timeout / 2).max(Duration::from_millis(1));
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    interval
+}
+
+/// Whether `request` asks for a part of a packet the leech was told about
+fn is_request_valid(
+    torrent_file: &TorrentFile,
+    announced: &BitVec,
+    request: &BlockRequest,
+) -> bool {
+    announced.get(request.index).unwrap_or(false)
+        && request.length > 0
+        && request.length <= MAX_BLOCK_LEN
+        && request.begin + request.length <= torrent_file.packet_len(request.index)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+    use std::path::Path;
+    use std::str::FromStr;
+    use tokio::io::AsyncRead;
+
+    /// Starts seeding `content` in packets of `packet_size` bytes on `addr`, returning its metainfo
+    async fn start_seed(
+        addr: SocketAddr,
+        filename: &str,
+        content: &[u8],
+        packet_size: usize,
+    ) -> Metainfo {
+        start_seed_with(addr, filename, content, packet_size, |seed| seed).await
+    }
+
+    /// Like `start_seed`, with the seed set up by `configure` first
+    async fn start_seed_with(
+        addr: SocketAddr,
+        filename: &str,
+        content: &[u8],
+        packet_size: usize,
+        configure: impl FnOnce(Client) -> Client,
+    ) -> Metainfo {
+        std::fs::write(filename, content).unwrap();
+        let metainfo = Metainfo::create(filename, packet_size, "tcp://127.0.0.1:1")
+            .await
+            .unwrap();
+        let seed = configure(Client::new(
+            addr,
+            metainfo.info_hash(),
+            TorrentFile::from_complete(filename, packet_size).unwrap(),
+        ));
+        tokio::spawn(async move { seed.do_seed_loop().await });
+        time::sleep(Duration::from_millis(50)).await;
+        metainfo
+    }
+
+    /// Downloads everything the seed at `seed_addr` has, failing after 5 seconds
+    async fn download_from(leech: &Client, seed_addr: SocketAddr) -> Download {
+        let download = leech.start_download().await;
+        time::timeout(
+            Duration::from_secs(5),
+            download.download_from_peer(seed_addr),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        download
+    }
+
+    async fn read_message<R: AsyncRead + Unpin>(frames: &mut FrameReader<R>) -> PeerMessage {
+        time::timeout(Duration::from_secs(1), PeerMessage::read_from(frames))
+            .await
+            .expect("Seed didn't respond")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn Client_serves_leeches_concurrently() {
+        let addr = SocketAddr::from_str("127.0.0.1:46101").unwrap();
+        let filename = ".testfiles/Client_serves_leeches_concurrently";
+        let metainfo = start_seed(addr, filename, b"ABCDabcd", 4).await;
+
+        let _idle = TcpStream::connect(addr).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = Handshake {
+            info_hash: metainfo.info_hash(),
+            peer_id: generate_peer_id(),
         };
+        handshake.write_to(&mut stream).await.unwrap();
+        let seed_handshake =
+            time::timeout(Duration::from_secs(1), Handshake::read_from(&mut stream))
+                .await
+                .expect("Seed didn't respond while another leech was idle")
+                .unwrap();
+        assert_eq!(seed_handshake.info_hash, metainfo.info_hash());
+
+        let mut frames = FrameReader::new(&mut stream, max_message_len(2));
+        assert_eq!(
+            read_message(&mut frames).await,
+            PeerMessage::Bitfield(BitVec::from_bytes(&[0b1100_0000]))
+        );
+    }
+
+    #[tokio::test]
+    async fn Client_answers_pipelined_requests() {
+        let addr = SocketAddr::from_str("127.0.0.1:46102").unwrap();
+        let filename = ".testfiles/Client_answers_pipelined_requests";
+        let metainfo = start_seed(addr, filename, b"ABCDabcd", 4).await;
+
+        // Built by hand from the specification, as another client would send them
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend([0; 8]);
+        handshake.extend(metainfo.info_hash());
+        handshake.extend(b"-XX0000-000000000000");
+        let interested = [0, 0, 0, 1, 2];
+        let requests = [
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2],
+            [0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4],
+        ]
+        .concat();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(&[&handshake[..], &interested].concat())
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 68];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..48], &handshake[..48]);
+
+        let (reader, mut writer) = stream.split();
+        let mut frames = FrameReader::new(reader, max_message_len(2));
+        assert_eq!(read_message(&mut frames).await.to_bytes(), [5, 0b1100_0000]);
+        assert_eq!(read_message(&mut frames).await.to_bytes(), [1]);
+
+        // The second request is split between two writes
+        let (first, second) = requests.split_at(requests.len() - 3);
+        writer.write_all(first).await.unwrap();
+        writer.flush().await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        writer.write_all(second).await.unwrap();
+
+        assert_eq!(
+            read_message(&mut frames).await.to_bytes(),
+            [7, 0, 0, 0, 1, 0, 0, 0, 2, b'c', b'd']
+        );
+        assert_eq!(
+            read_message(&mut frames).await.to_bytes(),
+            [7, 0, 0, 0, 0, 0, 0, 0, 0, b'A', b'B', b'C', b'D']
+        );
+    }
+
+    /// Swarms with aria2, a reference client, which downloads the torrent from the client
+    ///
+    /// Needs `aria2c` installed, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn Client_seeds_to_reference_client() {
+        if std::process::Command::new("aria2c")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            println!("aria2c isn't installed, skipping");
+            return;
+        }
+
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46009").unwrap();
+        start_tracker(tracker_addr, Duration::from_secs(60)).await;
+
+        let content: Vec<u8> = (0..100_000).map(|i| (i % 227) as u8).collect();
+        let sent = ".testfiles/Client_seeds_to_reference_client";
+        let torrent = ".testfiles/Client_seeds_to_reference_client.torrent";
+        let download_dir = ".testfiles/Client_seeds_to_reference_client_aria2";
+        std::fs::write(sent, &content).unwrap();
+        let _ = std::fs::remove_dir_all(download_dir);
+        let metainfo = Metainfo::create(sent, 32_768, &format!("http://{tracker_addr}/announce"))
+            .await
+            .unwrap();
+        metainfo.save_to_file(torrent).await.unwrap();
+
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46133").unwrap();
+        let seed = Client::from_existing(seed_addr, sent, &metainfo)
+            .await
+            .unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { seed.seed_loop(shutdown_rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let aria2 = tokio::process::Command::new("aria2c")
+            .args([
+                "--dir",
+                download_dir,
+                "--seed-time=0",
+                "--enable-dht=false",
+                "--bt-enable-lpd=false",
+                "--enable-peer-exchange=false",
+                "--console-log-level=warn",
+                torrent,
+            ])
+            .status();
+        let status = time::timeout(Duration::from_secs(30), aria2)
+            .await
+            .expect("aria2c didn't finish the download")
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read(Path::new(download_dir).join(metainfo.info().name())).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn Client_rejects_unknown_torrent() {
+        let addr = SocketAddr::from_str("127.0.0.1:46103").unwrap();
+        let filename = ".testfiles/Client_rejects_unknown_torrent";
+        start_seed(addr, filename, b"ABCDabcd", 4).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        Handshake {
+            info_hash: [1; 20],
+            peer_id: generate_peer_id(),
+        }
+        .write_to(&mut stream)
+        .await
+        .unwrap();
 
-        let peer_count = peerlist.len();
-        let mut seed = peer_count * packet_index;
-
-        // Code taken from `https://github.com/rust-lang/rust/blob/6a179026decb823e6ad8ba1c81729528bc5d695f/library/core/src/slice/sort.rs#L677`
-        // Pseudorandom number generator from the "Xorshift RNGs" paper by George Marsaglia.
-        let mut gen_usize = || {
-            if usize::BITS <= 32 {
-                let mut r = seed as u32;
-                r ^= r << 13;
-                r ^= r >> 17;
-                r ^= r << 5;
-                seed = r as usize;
-                seed
-            } else {
-                let mut r = seed as u64;
-                r ^= r << 13;
-                r ^= r >> 7;
-                r ^= r << 17;
-                seed = r as usize;
-                seed
+        let mut buf = [0u8; 1];
+        let bytes_read = time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes_read, 0);
+    }
+
+    #[tokio::test]
+    async fn Client_announces_over_http_and_udp() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46004").unwrap();
+        let tracker = Arc::new(crate::tracker::Tracker::new());
+        let (_tcp_wx, tcp_rx) = oneshot::channel();
+        let (_udp_wx, udp_rx) = oneshot::channel();
+        tokio::spawn({
+            let tracker = Arc::clone(&tracker);
+            async move { tracker.listen(&tracker_addr, tcp_rx).await }
+        });
+        tokio::spawn(async move { tracker.listen_udp(&tracker_addr, udp_rx).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let filename = ".testfiles/Client_announces_over_http_and_udp";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = |port| {
+            Client::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                [0; 20],
+                TorrentFile::from_complete(filename, 4).unwrap(),
+            )
+        };
+        let (http_client, udp_client) = (client(46106), client(46107));
+
+        let response = http_client
+            .announce(&format!("http://{tracker_addr}/announce"), None)
+            .await
+            .unwrap();
+        assert!(response.peers.is_empty());
+
+        let udp_url = format!("udp://{tracker_addr}/announce");
+        for _ in 0..2 {
+            let response = udp_client.announce(&udp_url, None).await.unwrap();
+            assert_eq!(response.seeders, 2);
+            assert_eq!(
+                response
+                    .peers
+                    .iter()
+                    .map(|peer| peer.address)
+                    .collect::<Vec<_>>(),
+                vec![http_client.address]
+            );
+        }
+
+        let expected = ScrapeStats {
+            seeders: 2,
+            completed: 0,
+            leechers: 0,
+        };
+        let http_url = format!("http://{tracker_addr}/announce");
+        assert_eq!(http_client.scrape(&http_url).await.unwrap(), expected);
+        assert_eq!(udp_client.scrape(&udp_url).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn Client_reads_long_peer_lists() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46005").unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            crate::tracker::Tracker::new()
+                .listen(&tracker_addr, shutdown_rx)
+                .await
+        });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // IPv6 peers take the most space, the response is longer than a single small read
+        let registered: HashSet<SocketAddr> = (1000..1100)
+            .map(|port| SocketAddr::from_str(&format!("[::1]:{port}")).unwrap())
+            .collect();
+        let mut stream = TcpStream::connect(tracker_addr).await.unwrap();
+        for peer in &registered {
+            let mut request =
+                serde_json::to_vec(&RequestToTracker::RegisterAsPeer([0; 20], *peer, 0, 0, 0))
+                    .unwrap();
+            request.push(b'\n');
+            stream.write_all(&request).await.unwrap();
+        }
+        let mut responses = BufReader::new(stream).lines();
+        for _ in &registered {
+            responses.next_line().await.unwrap().unwrap();
+        }
+
+        let filename = ".testfiles/Client_reads_long_peer_lists";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = Client::new(
+            SocketAddr::from_str("127.0.0.1:46108").unwrap(),
+            [0; 20],
+            TorrentFile::from_complete(filename, 4).unwrap(),
+        );
+        let peers = client.request_peerlist(&tracker_addr).await.unwrap();
+        assert_eq!(peers.len(), 50);
+        assert!(peers.iter().all(|peer| registered.contains(peer)));
+    }
+
+    /// Starts a JSON tracker on `addr`, asking peers to announce every `announce_interval`
+    async fn start_tracker(addr: SocketAddr, announce_interval: Duration) {
+        let (shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _shutdown_wx = shutdown_wx;
+            crate::tracker::Tracker::new()
+                .with_announce_interval(announce_interval)
+                .listen(&addr, shutdown_rx)
+                .await
+        });
+        time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn Client_announces_over_a_single_json_connection() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46010").unwrap();
+        let other_peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        // Accepts a single connection, any other one is refused
+        let listener = TcpListener::bind(tracker_addr).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(listener);
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let response = match serde_json::from_str(&line).unwrap() {
+                    RequestToTracker::ReportCompleted(..) => TrackerResponse::CompletionRecorded,
+                    RequestToTracker::RegisterAsPeer(..) => {
+                        TrackerResponse::RegisteredSuccesfully(Duration::from_secs(60))
+                    }
+                    RequestToTracker::GetPeers(..) => TrackerResponse::Peers(vec![other_peer]),
+                    RequestToTracker::Scrape(..) => TrackerResponse::Stats(vec![ScrapeStats {
+                        seeders: 2,
+                        completed: 1,
+                        leechers: 0,
+                    }]),
+                    _ => TrackerResponse::InvalidRequest,
+                };
+                let mut response = serde_json::to_vec(&response).unwrap();
+                response.push(b'\n');
+                writer.write_all(&response).await.unwrap();
             }
+        });
+
+        let filename = ".testfiles/Client_announces_over_a_single_json_connection";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = Client::new(
+            SocketAddr::from_str("127.0.0.1:46137").unwrap(),
+            [0; 20],
+            TorrentFile::from_complete(filename, 4).unwrap(),
+        );
+        let response = client
+            .announce(
+                &format!("tcp://{tracker_addr}"),
+                Some(AnnounceEvent::Completed),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.interval, Duration::from_secs(60));
+        assert_eq!(response.seeders, 2);
+        assert_eq!(response.peers, vec![AnnouncedPeer::from(other_peer)]);
+    }
+
+    #[tokio::test]
+    async fn Client_fails_over_to_next_tracker_tier() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46007").unwrap();
+        start_tracker(tracker_addr, Duration::from_secs(60)).await;
+
+        let filename = ".testfiles/Client_fails_over_to_next_tracker_tier";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = |port| {
+            Client::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                [0; 20],
+                TorrentFile::from_complete(filename, 4).unwrap(),
+            )
+            // Nothing listens on the first tier's trackers
+            .with_trackers(vec![
+                vec![
+                    "http://127.0.0.1:1/announce".to_owned(),
+                    "tcp://127.0.0.1:1".to_owned(),
+                ],
+                vec![format!("tcp://{tracker_addr}")],
+            ])
         };
+        let (first, second) = (client(46109), client(46110));
 
-        loop {
-            let peer_addr = peerlist[gen_usize() % peer_count];
-            let mut stream = TcpStream::connect(peer_addr).await?;
+        first.announce_to_trackers(None).await.unwrap();
+        let response = second
+            .announce_to_trackers(Some(AnnounceEvent::Started))
+            .await
+            .unwrap();
+        assert_eq!(response.seeders, 2);
+        assert_eq!(response.peers, vec![AnnouncedPeer::from(first.address)]);
+        assert!(second
+            .swarm_peers
+            .lock()
+            .unwrap()
+            .contains_key(&first.address));
+        assert_eq!(
+            second.trackers.lock().await.urls().last().unwrap(),
+            &format!("tcp://{tracker_addr}")
+        );
 
-            let seed_response = {
-                let mut availability_buf = vec![0u8; self.torrent_file.packet_size() / 8];
-                stream
-                    .write_all(&serde_json::to_vec(&LeechRequest::GetAvailability)?)
-                    .await?;
-                let bytes_read = stream.read(&mut availability_buf).await?;
-                serde_json::from_slice::<SeedResponse>(&availability_buf[..bytes_read])
-            }?;
+        let no_trackers = Client::new(
+            SocketAddr::from(([127, 0, 0, 1], 46111)),
+            [0; 20],
+            TorrentFile::from_complete(filename, 4).unwrap(),
+        );
+        assert!(no_trackers.announce_to_trackers(None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn Client_reannounces_at_tracker_interval() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46008").unwrap();
+        // Peers which don't announce again within 200ms are dropped
+        start_tracker(tracker_addr, Duration::from_millis(100)).await;
+
+        let filename = ".testfiles/Client_reannounces_at_tracker_interval";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = |port| {
+            Client::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                [0; 20],
+                TorrentFile::from_complete(filename, 4).unwrap(),
+            )
+            .with_trackers(vec![vec![format!("tcp://{tracker_addr}")]])
+            .with_min_announce_interval(Duration::from_millis(20))
+        };
+        let seed = Arc::new(client(46112));
+        let (shutdown_wx, shutdown_rx) = oneshot::channel();
+        let seed_handle = tokio::spawn({
+            let seed = Arc::clone(&seed);
+            async move { seed.seed_loop(shutdown_rx).await }
+        });
+
+        let observer = client(46113);
+        time::sleep(Duration::from_millis(500)).await;
+        let response = observer.announce_to_trackers(None).await.unwrap();
+        assert_eq!(response.peers, vec![AnnouncedPeer::from(seed.address)]);
+
+        // The seed tells the tracker it left
+        shutdown_wx.send(()).unwrap();
+        seed_handle.await.unwrap().unwrap();
+        let response = observer.announce_to_trackers(None).await.unwrap();
+        assert!(response.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn Client_backs_off_from_failing_peers() {
+        let path = ".testfiles/Client_backs_off_from_failing_peers";
+        std::fs::write(path, b"ABCDabcd").unwrap();
+        let metainfo = Metainfo::create(path, 4, "tcp://127.0.0.1:1")
+            .await
+            .unwrap();
+        let received = ".testfiles/Client_backs_off_from_failing_peers_received";
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46134").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo).unwrap();
+
+        // Nothing listens there, the leech shouldn't keep reconnecting
+        let dead_peer = SocketAddr::from_str("127.0.0.1:46135").unwrap();
+        let other_peer = SocketAddr::from_str("127.0.0.1:46136").unwrap();
+        leech.update_swarm_peers(&[AnnouncedPeer::from(dead_peer)]);
+        assert!(
+            time::timeout(Duration::from_millis(500), leech.do_leech_loop())
+                .await
+                .is_err()
+        );
+        assert_eq!(leech.swarm_peers.lock().unwrap()[&dead_peer].failures, 1);
+
+        // Failures are kept while trackers list the peer, peers they don't list are dropped
+        leech.update_swarm_peers(&[
+            AnnouncedPeer::from(dead_peer),
+            AnnouncedPeer::from(other_peer),
+        ]);
+        leech.update_swarm_peers(&[AnnouncedPeer::from(dead_peer)]);
+        assert_eq!(leech.swarm_peers.lock().unwrap()[&dead_peer].failures, 1);
+        assert!(!leech.swarm_peers.lock().unwrap().contains_key(&other_peer));
+
+        for _ in 1..MAX_PEER_FAILURES {
+            leech.peer_failed(dead_peer);
+        }
+        assert!(leech.swarm_peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn Client_shutdown_doesnt_wait_for_unresponsive_trackers() {
+        // Receives announces, but never answers them
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46011").unwrap();
+        let _tracker = tokio::net::UdpSocket::bind(tracker_addr).await.unwrap();
+
+        let filename = ".testfiles/Client_shutdown_doesnt_wait_for_unresponsive_trackers";
+        std::fs::write(filename, "ABCDabcd").unwrap();
+        let client = Client::new(
+            SocketAddr::from_str("127.0.0.1:46138").unwrap(),
+            [0; 20],
+            TorrentFile::from_complete(filename, 4).unwrap(),
+        )
+        .with_trackers(vec![vec![format!("udp://{tracker_addr}/announce")]]);
+        client.started.store(true, Ordering::Relaxed);
+
+        let (shutdown_wx, shutdown_rx) = oneshot::channel();
+        shutdown_wx.send(()).unwrap();
+        let start = Instant::now();
+        assert!(client.seed_loop(shutdown_rx).await.is_err());
+        assert_eq!(start.elapsed(), STOPPED_ANNOUNCE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn Client_leech_announces_stopped_on_shutdown() {
+        let tracker_addr = SocketAddr::from_str("127.0.0.1:46013").unwrap();
+        start_tracker(tracker_addr, Duration::from_secs(60)).await;
+
+        let sent = ".testfiles/Client_leech_announces_stopped_on_shutdown_sent";
+        let received = ".testfiles/Client_leech_announces_stopped_on_shutdown_received";
+        std::fs::write(sent, "ABCDabcd").unwrap();
+        let metainfo = Metainfo::create(sent, 4, "tcp://127.0.0.1:1")
+            .await
+            .unwrap();
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46143").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo)
+            .unwrap()
+            .with_trackers(vec![vec![format!("tcp://{tracker_addr}")]]);
+
+        let (shutdown_wx, shutdown_rx) = oneshot::channel();
+        let shutdown = async {
+            time::sleep(Duration::from_millis(200)).await;
+            let peers = leech.request_peerlist(&tracker_addr).await.unwrap();
+            assert_eq!(peers, vec![leech_addr]);
+            shutdown_wx.send(()).unwrap();
+        };
+        let (res, ()) = tokio::join!(leech.leech_loop(shutdown_rx), shutdown);
+        res.unwrap();
+
+        assert!(leech
+            .request_peerlist(&tracker_addr)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn Client_downloads_packets_in_blocks() {
+        // Packets span several blocks, the last one is shorter
+        let content: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        let packet_size = 2 * BLOCK_LEN;
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46104").unwrap();
+        let sent = ".testfiles/Client_downloads_packets_in_blocks_sent";
+        let received = ".testfiles/Client_downloads_packets_in_blocks_received";
+        let metainfo = start_seed(seed_addr, sent, &content, packet_size).await;
+
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46105").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo).unwrap();
+        let download = download_from(&leech, seed_addr).await;
+
+        assert!(download.is_done());
+        assert_eq!(std::fs::read(received).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn Client_counts_transferred_bytes() {
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46141").unwrap();
+        let sent = ".testfiles/Client_counts_transferred_bytes_sent";
+        let received = ".testfiles/Client_counts_transferred_bytes_received";
+        std::fs::write(sent, "ABCDabcdAB").unwrap();
+        let metainfo = Metainfo::create(sent, 4, "tcp://127.0.0.1:1")
+            .await
+            .unwrap();
+        let seed = Arc::new(Client::new(
+            seed_addr,
+            metainfo.info_hash(),
+            TorrentFile::from_complete(sent, 4).unwrap(),
+        ));
+        tokio::spawn({
+            let seed = Arc::clone(&seed);
+            async move { seed.do_seed_loop().await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
 
-            if let SeedResponse::Availability(availability) = seed_response {
-                match availability.get(packet_index) {
-                    Some { 0: true } => break Ok(stream), // is Some(true) - peer has the packet
-                    _ => continue,
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46142").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo).unwrap();
+        download_from(&leech, seed_addr).await;
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(leech.downloaded.load(Ordering::Relaxed), 10);
+        assert_eq!(leech.uploaded.load(Ordering::Relaxed), 0);
+        assert_eq!(seed.uploaded.load(Ordering::Relaxed), 10);
+        assert_eq!(seed.downloaded.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn Client_resumes_interrupted_download() {
+        let content: Vec<u8> = (0..4096).map(|i| (i % 253) as u8).collect();
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46114").unwrap();
+        let sent = ".testfiles/Client_resumes_interrupted_download_sent";
+        let received = ".testfiles/Client_resumes_interrupted_download_received";
+        let metainfo = start_seed(seed_addr, sent, &content, 1024).await;
+        let _ = std::fs::remove_file(format!("{received}.progress"));
+
+        // An interrupted download got the first 3 packets, the third one got corrupted on disk since
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46115").unwrap();
+        let leech = Client::resume(leech_addr, received, &metainfo, false)
+            .await
+            .unwrap();
+        leech
+            .torrent_file
+            .write_packets(0, &content[..3072])
+            .await
+            .unwrap();
+        leech.save_progress().await.unwrap();
+        drop(leech);
+        let mut data = std::fs::read(received).unwrap();
+        data[2048] ^= 1;
+        std::fs::write(received, data).unwrap();
+
+        let leech = Client::resume(leech_addr, received, &metainfo, true)
+            .await
+            .unwrap();
+        assert_eq!(leech.torrent_file.bytes_left().await, 2048);
+
+        let download = download_from(&leech, seed_addr).await;
+        assert!(download.is_done());
+        assert_eq!(std::fs::read(received).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn Client_saves_progress_every_n_packets() {
+        let content: Vec<u8> = (0..4096).map(|i| (i % 247) as u8).collect();
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46116").unwrap();
+        let sent = ".testfiles/Client_saves_progress_every_n_packets_sent";
+        let received = ".testfiles/Client_saves_progress_every_n_packets_received";
+        let metainfo = start_seed(seed_addr, sent, &content, 1024).await;
+        let _ = std::fs::remove_file(format!("{received}.progress"));
+
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46117").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo)
+            .unwrap()
+            .with_save_every_packets(3);
+        download_from(&leech, seed_addr).await;
+
+        // Saved after the third packet, but not after the fourth
+        let progress = TorrentFile::from_progress_file(&format!("{received}.progress"))
+            .await
+            .unwrap();
+        assert_eq!(progress.bytes_left().await, 1024);
+        assert!(!Path::new(&format!("{received}.progress.tmp")).exists());
+    }
+
+    #[tokio::test]
+    async fn Client_rechecks_existing_data() {
+        let content: Vec<u8> = (0..4000).map(|i| (i % 241) as u8).collect();
+        let path = ".testfiles/Client_rechecks_existing_data";
+        std::fs::write(path, &content).unwrap();
+        let metainfo = Metainfo::create(path, 1024, "tcp://127.0.0.1:1")
+            .await
+            .unwrap();
+
+        // Second packet corrupted, last one cut short
+        let mut data = content.clone();
+        data[1500] ^= 1;
+        data.truncate(3500);
+        std::fs::write(path, &data).unwrap();
+
+        let addr = SocketAddr::from_str("127.0.0.1:46118").unwrap();
+        let client = Client::from_existing(addr, path, &metainfo).await.unwrap();
+        let mut expected = BitVec::from_elem(4, true);
+        expected.set(1, false);
+        expected.set(3, false);
+        assert_eq!(
+            client.torrent_file.read_packet_availability().await,
+            expected
+        );
+        assert_eq!(std::fs::read(path).unwrap(), data);
+
+        // Fixed behind the client's back
+        std::fs::write(path, &content).unwrap();
+        assert_eq!(client.recheck().await.unwrap(), 4);
+        assert_eq!(client.torrent_file.bytes_left().await, 0);
+    }
+
+    #[tokio::test]
+    async fn Client_endgame_requests_stalled_packets_elsewhere() {
+        let content: Vec<u8> = (0..4096).map(|i| (i % 239) as u8).collect();
+        let sent = ".testfiles/Client_endgame_requests_stalled_packets_elsewhere_sent";
+        let received = ".testfiles/Client_endgame_requests_stalled_packets_elsewhere_received";
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46120").unwrap();
+        let metainfo = start_seed(seed_addr, sent, &content, 1024).await;
+        let info_hash = metainfo.info_hash();
+
+        // Has every packet, but never answers requests, counting cancelled blocks instead
+        let stalled_addr = SocketAddr::from_str("127.0.0.1:46119").unwrap();
+        let listener = TcpListener::bind(stalled_addr).await.unwrap();
+        let stalled = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Handshake::read_from(&mut stream).await.unwrap();
+            let handshake = Handshake {
+                info_hash,
+                peer_id: generate_peer_id(),
+            };
+            handshake.write_to(&mut stream).await.unwrap();
+            PeerMessage::Bitfield(BitVec::from_bytes(&[0b1111_0000]))
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            PeerMessage::Unchoke.write_to(&mut stream).await.unwrap();
+
+            let mut frames = FrameReader::new(stream, max_message_len(4));
+            let mut cancelled = 0;
+            while let Some(message) = PeerMessage::read_from(&mut frames).await.unwrap() {
+                if let PeerMessage::Cancel(_) = message {
+                    cancelled += 1;
                 }
             }
+            cancelled
+        });
+
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46121").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo).unwrap();
+        let download = Arc::new(leech.start_download().await);
+
+        // The stalled peer gets every packet assigned before the seed connects
+        let stalled_download = tokio::spawn({
+            let download = Arc::clone(&download);
+            async move { download.download_from_peer(stalled_addr).await }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        time::timeout(
+            Duration::from_secs(5),
+            download.download_from_peer(seed_addr),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(download.is_done());
+        assert_eq!(std::fs::read(received).unwrap(), content);
+        stalled_download.await.unwrap().unwrap();
+        assert_eq!(stalled.await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn Client_chokes_leeches_beyond_upload_slots() {
+        let addr = SocketAddr::from_str("127.0.0.1:46122").unwrap();
+        let filename = ".testfiles/Client_chokes_leeches_beyond_upload_slots";
+        let metainfo = start_seed_with(addr, filename, b"ABCDabcd", 4, |seed| {
+            seed.with_upload_slots(1)
+        })
+        .await;
+
+        let mut leeches = vec![];
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake {
+                info_hash: metainfo.info_hash(),
+                peer_id: generate_peer_id(),
+            };
+            handshake.write_to(&mut stream).await.unwrap();
+            Handshake::read_from(&mut stream).await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut frames = FrameReader::new(reader, max_message_len(2));
+            assert!(matches!(
+                read_message(&mut frames).await,
+                PeerMessage::Bitfield(_)
+            ));
+            PeerMessage::Interested.write_to(&mut writer).await.unwrap();
+            leeches.push((frames, writer));
+            // Makes the first leech the one unchoked
+            time::sleep(Duration::from_millis(20)).await;
         }
+
+        assert!(matches!(
+            read_message(&mut leeches[0].0).await,
+            PeerMessage::Unchoke
+        ));
+        let second = time::timeout(
+            Duration::from_millis(200),
+            PeerMessage::read_from(&mut leeches[1].0),
+        );
+        assert!(second.await.is_err());
+
+        // The slot frees up once the first leech loses interest
+        PeerMessage::NotInterested
+            .write_to(&mut leeches[0].1)
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_message(&mut leeches[0].0).await,
+            PeerMessage::Choke
+        ));
+        assert!(matches!(
+            read_message(&mut leeches[1].0).await,
+            PeerMessage::Unchoke
+        ));
+    }
+
+    #[tokio::test]
+    async fn Client_limits_leech_connections() {
+        let addr = SocketAddr::from_str("127.0.0.1:46139").unwrap();
+        let filename = ".testfiles/Client_limits_leech_connections";
+        let metainfo = start_seed_with(addr, filename, b"ABCDabcd", 4, |seed| {
+            seed.with_max_connections(1)
+        })
+        .await;
+        let handshake = Handshake {
+            info_hash: metainfo.info_hash(),
+            peer_id: generate_peer_id(),
+        };
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        handshake.write_to(&mut first).await.unwrap();
+        Handshake::read_from(&mut first).await.unwrap();
+
+        // Queued by the OS, but not served while the first leech is
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        handshake.write_to(&mut second).await.unwrap();
+        let waiting = time::timeout(
+            Duration::from_millis(200),
+            Handshake::read_from(&mut second),
+        );
+        assert!(waiting.await.is_err());
+
+        drop(first);
+        time::timeout(Duration::from_secs(1), Handshake::read_from(&mut second))
+            .await
+            .expect("Seed didn't serve the second leech")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn Client_keeps_choked_leeches_connected() {
+        let content: Vec<u8> = (0..32_768).map(|i| (i % 229) as u8).collect();
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46130").unwrap();
+        let sent = ".testfiles/Client_keeps_choked_leeches_connected_sent";
+
+        // Serving one leech takes several idle timeouts, while the other one waits choked
+        let idle_timeout = Duration::from_millis(200);
+        let metainfo = start_seed_with(seed_addr, sent, &content, 4096, |seed| {
+            seed.with_upload_slots(1)
+                .with_upload_limit(32_768)
+                .with_idle_timeout(idle_timeout)
+        })
+        .await;
+
+        let mut received = vec![];
+        let mut downloads = JoinSet::new();
+        for port in [46131, 46132] {
+            let path = format!(".testfiles/Client_keeps_choked_leeches_connected_{port}");
+            let leech =
+                Client::from_metainfo(SocketAddr::from(([127, 0, 0, 1], port)), &path, &metainfo)
+                    .unwrap()
+                    .with_idle_timeout(idle_timeout);
+            received.push(path);
+            downloads.spawn(async move {
+                let download = leech.start_download().await;
+                download.download_from_peer(seed_addr).await
+            });
+        }
+        while let Some(res) = time::timeout(Duration::from_secs(5), downloads.join_next())
+            .await
+            .unwrap()
+        {
+            res.unwrap().unwrap();
+        }
+
+        for path in received {
+            assert_eq!(std::fs::read(path).unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn Client_limits_upload_rate() {
+        let content: Vec<u8> = (0..32_768).map(|i| (i % 233) as u8).collect();
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46123").unwrap();
+        let sent = ".testfiles/Client_limits_upload_rate_sent";
+        let received = ".testfiles/Client_limits_upload_rate_received";
+
+        // The bucket starts empty, so that takes a second
+        let metainfo = start_seed_with(seed_addr, sent, &content, 8192, |seed| {
+            seed.with_upload_limit(32_768)
+        })
+        .await;
+
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46124").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo).unwrap();
+        let start = Instant::now();
+        download_from(&leech, seed_addr).await;
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+        assert_eq!(std::fs::read(received).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn Client_streams_requested_bytes_first() {
+        let content: Vec<u8> = (0..49_152).map(|i| (i % 239) as u8).collect();
+        let seed_addr = SocketAddr::from_str("127.0.0.1:46127").unwrap();
+        let sent = ".testfiles/Client_streams_requested_bytes_first_sent";
+        let received = ".testfiles/Client_streams_requested_bytes_first_received";
+
+        // A packet a second, the whole download takes 6 seconds
+        let metainfo = start_seed_with(seed_addr, sent, &content, 8192, |seed| {
+            seed.with_upload_limit(8192)
+        })
+        .await;
+
+        let leech_addr = SocketAddr::from_str("127.0.0.1:46128").unwrap();
+        let stream_addr = SocketAddr::from_str("127.0.0.1:46129").unwrap();
+        let leech = Client::from_metainfo(leech_addr, received, &metainfo)
+            .unwrap()
+            .with_streaming()
+            .with_pipeline_depth(1);
+        let leech = Arc::new(leech);
+        let (_shutdown_sender, shutdown_receiver) = oneshot::channel();
+        tokio::spawn({
+            let leech = Arc::clone(&leech);
+            async move { leech.stream_loop(stream_addr, shutdown_receiver).await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // The request waits for the last packet, which is downloaded first
+        let mut stream = TcpStream::connect(stream_addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nRange: bytes=-100\r\n\r\n")
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        tokio::spawn({
+            let leech = Arc::clone(&leech);
+            async move {
+                leech
+                    .start_download()
+                    .await
+                    .download_from_peer(seed_addr)
+                    .await
+            }
+        });
+
+        let mut response = vec![];
+        time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.ends_with(&content[content.len() - 100..]));
+        assert!(leech.torrent_file.bytes_left().await > 0);
     }
 }
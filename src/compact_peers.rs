@@ -0,0 +1,131 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use tokio::io;
+
+/// Splits `peers` into compact IPv4 (BEP 23) and IPv6 (BEP 7) lists, with 6 and 18 bytes per
+/// peer respectively: the address followed by the big-endian port
+pub fn encode(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut peers4 = vec![];
+    let mut peers6 = vec![];
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                peers4.extend(ip.octets());
+                peers4.extend(peer.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                peers6.extend(ip.octets());
+                peers6.extend(peer.port().to_be_bytes());
+            }
+        }
+    }
+    (peers4, peers6)
+}
+
+/// Decodes a compact list of IPv4 peers, or IPv6 peers if `ipv6` is set
+pub fn decode(bytes: &[u8], ipv6: bool) -> io::Result<Vec<SocketAddr>> {
+    let peer_len = if ipv6 { 18 } else { 6 };
+    if !bytes.len().is_multiple_of(peer_len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid compact peer list.",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(peer_len)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(peer_len - 2);
+            let ip = match ipv6 {
+                false => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+                true => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
+        })
+        .collect())
+}
+
+/// Serializes peers as a pair of hex-encoded compact lists (IPv4, IPv6), for `#[serde(with)]`
+pub mod hex {
+    use std::net::SocketAddr;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::metainfo::to_hex;
+
+    pub fn serialize<S>(peers: &[SocketAddr], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (peers4, peers6) = super::encode(peers);
+        (to_hex(&peers4), to_hex(&peers6)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (peers4, peers6) = <(String, String)>::deserialize(deserializer)?;
+        let mut peers = super::decode(&from_hex(&peers4).map_err(D::Error::custom)?, false)
+            .map_err(D::Error::custom)?;
+        peers.extend(
+            super::decode(&from_hex(&peers6).map_err(D::Error::custom)?, true)
+                .map_err(D::Error::custom)?,
+        );
+        Ok(peers)
+    }
+
+    fn from_hex(hex: &str) -> Result<Vec<u8>, &'static str> {
+        if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+            return Err("Invalid hex string.");
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "Invalid hex string."))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn compact_encoding() {
+        let peers: Vec<SocketAddr> = ["10.0.0.1:6881", "[::1]:6882", "10.0.0.2:80"]
+            .iter()
+            .map(|peer| SocketAddr::from_str(peer).unwrap())
+            .collect();
+
+        let (peers4, peers6) = encode(&peers);
+        assert_eq!(
+            peers4,
+            [10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80].to_vec()
+        );
+        assert_eq!(peers6.len(), 18);
+
+        assert_eq!(decode(&peers4, false).unwrap(), vec![peers[0], peers[2]]);
+        assert_eq!(decode(&peers6, true).unwrap(), vec![peers[1]]);
+        assert!(decode(&peers4[..5], false).is_err());
+    }
+
+    #[test]
+    fn hex_serde_roundtrip() {
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Peers(#[serde(with = "hex")] Vec<SocketAddr>);
+
+        let peers = Peers(vec![
+            SocketAddr::from_str("10.0.0.1:6881").unwrap(),
+            SocketAddr::from_str("[::1]:6882").unwrap(),
+        ]);
+        let json = serde_json::to_string(&peers).unwrap();
+        assert_eq!(
+            json,
+            r#"["0a0000011ae1","000000000000000000000000000000011ae2"]"#
+        );
+        assert_eq!(serde_json::from_str::<Peers>(&json).unwrap(), peers);
+        assert!(serde_json::from_str::<Peers>(r#"["0a00000","" ]"#).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Frames longer than this are never written; readers are given a limit of their own
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes a frame: 4-byte big-endian length of `body`, followed by `body`
+///
+/// Frames with an empty body are keep-alives
+pub async fn write_frame<W>(writer: &mut W, body: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame too long.",
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    writer.write_all(&frame).await
+}
+
+/// Splits a byte stream into frames written by `write_frame`
+///
+/// Bytes of incomplete frames are kept between calls, so `next_frame` can be cancelled
+/// (e.g. in `select!`) without losing data
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Longer frames are rejected instead of being buffered
+    max_frame_len: usize,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(reader: R, max_frame_len: usize) -> Self {
+        Self {
+            reader,
+            buf: vec![],
+            max_frame_len,
+        }
+    }
+
+    /// Returns the next frame's body, or `None` if the stream ended cleanly between frames
+    pub async fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if self.buf.len() >= 4 {
+                let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                if len > self.max_frame_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid frame length {len}."),
+                    ));
+                }
+                if self.buf.len() >= 4 + len {
+                    let body = self.buf[4..4 + len].to_vec();
+                    self.buf.drain(..4 + len);
+                    return Ok(Some(body));
+                }
+                self.buf.reserve(4 + len - self.buf.len());
+            }
+
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    #[tokio::test]
+    async fn FrameReader_roundtrip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, b"payload").await.unwrap();
+        write_frame(&mut buf, b"").await.unwrap();
+        assert_eq!(&buf[..5], &[0, 0, 0, 7, b'p']);
+
+        let mut frames = FrameReader::new(buf.as_slice(), MAX_FRAME_LEN);
+        assert_eq!(
+            frames.next_frame().await.unwrap(),
+            Some(b"payload".to_vec())
+        );
+        assert_eq!(frames.next_frame().await.unwrap(), Some(vec![]));
+        assert_eq!(frames.next_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn FrameReader_reassembles_partial_reads() {
+        let body = vec![7u8; 100_000];
+        // A small pipe forces the frame to arrive in many reads
+        let (mut writer, reader) = io::duplex(64);
+        let sent = body.clone();
+        tokio::spawn(async move { write_frame(&mut writer, &sent).await });
+
+        let mut frames = FrameReader::new(reader, MAX_FRAME_LEN);
+        assert_eq!(frames.next_frame().await.unwrap(), Some(body));
+    }
+
+    #[tokio::test]
+    async fn FrameReader_truncated_frame_is_error() {
+        let mut buf = vec![];
+        write_frame(&mut buf, b"payload").await.unwrap();
+
+        let mut frames = FrameReader::new(&buf[..6], MAX_FRAME_LEN);
+        assert!(frames.next_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn FrameReader_rejects_oversized_frame() {
+        let mut frames = FrameReader::new([0xffu8, 0xff, 0xff, 0xff, 0].as_slice(), MAX_FRAME_LEN);
+        assert!(frames.next_frame().await.is_err());
+
+        // Rejected from the length alone, before the body arrives
+        let mut buf = vec![];
+        write_frame(&mut buf, b"12345678").await.unwrap();
+        let mut frames = FrameReader::new(&buf[..], 8);
+        assert_eq!(
+            frames.next_frame().await.unwrap(),
+            Some(b"12345678".to_vec())
+        );
+        let mut frames = FrameReader::new(&buf[..4], 7);
+        let err = frames.next_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,865 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::bencode::{self, Value};
+use crate::compact_peers;
+use crate::metainfo::InfoHash;
+use crate::peer_wire::PeerId;
+use crate::requests::ScrapeStats;
+
+/// Tracker responses longer than this are rejected
+const MAX_RESPONSE_LEN: u64 = 1024 * 1024;
+/// HTTP requests with more header lines are dropped
+const MAX_HTTP_HEADERS: usize = 64;
+/// HTTP requests whose request line and headers are longer are dropped
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Announces other than regular refreshes
+pub enum AnnounceEvent {
+    /// First announce of a peer
+    Started,
+    /// The peer finished downloading
+    Completed,
+    /// The peer is leaving the swarm
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Parameters of `GET /announce`
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    /// Overrides the IP address the tracker sees the request coming from
+    pub ip: Option<IpAddr>,
+    /// Port the peer accepts connections on
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes the peer still has to download
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    /// Number of peers wanted, the tracker's default if `None`
+    pub numwant: Option<u32>,
+    /// Asks for peers in compact form (BEP 23), which most trackers expect clients to accept
+    pub compact: bool,
+}
+
+impl AnnounceRequest {
+    /// Encodes the request as a URL query string
+    pub fn to_query(&self) -> String {
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}",
+            percent_encode(&self.info_hash),
+            percent_encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            query.push_str(&format!("&event={}", event.as_str()));
+        }
+        if let Some(ip) = self.ip {
+            query.push_str(&format!("&ip={ip}"));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push_str(&format!("&numwant={numwant}"));
+        }
+        if self.compact {
+            query.push_str("&compact=1");
+        }
+        query
+    }
+
+    /// Parses a URL query string, ignoring parameters it doesn't know
+    pub fn from_query(query: &str) -> io::Result<Self> {
+        let mut params = BTreeMap::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            params.insert(key, percent_decode(value)?);
+        }
+
+        let param = |key: &str| {
+            params.get(key).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Missing parameter {key}."),
+                )
+            })
+        };
+        let parse = |key: &str| -> io::Result<u64> {
+            std::str::from_utf8(param(key)?)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid parameter {key}."),
+                    )
+                })
+        };
+        let hash = |key: &str| -> io::Result<[u8; 20]> {
+            param(key)?.as_slice().try_into().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Parameter {key} isn't 20 bytes long."),
+                )
+            })
+        };
+
+        let event = match params.get("event").map(Vec::as_slice) {
+            None | Some(b"") => None,
+            Some(b"started") => Some(AnnounceEvent::Started),
+            Some(b"completed") => Some(AnnounceEvent::Completed),
+            Some(b"stopped") => Some(AnnounceEvent::Stopped),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid parameter event.",
+                ))
+            }
+        };
+        // Hostnames aren't resolved, the connection's address is used instead
+        let ip = params
+            .get("ip")
+            .and_then(|ip| std::str::from_utf8(ip).ok())
+            .and_then(|ip| IpAddr::from_str(ip).ok());
+        let numwant = match params.contains_key("numwant") {
+            true => Some(u32::try_from(parse("numwant")?).unwrap_or(u32::MAX)),
+            false => None,
+        };
+
+        Ok(Self {
+            info_hash: hash("info_hash")?,
+            peer_id: hash("peer_id")?,
+            ip,
+            port: u16::try_from(parse("port")?).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter port.")
+            })?,
+            uploaded: parse("uploaded")?,
+            downloaded: parse("downloaded")?,
+            left: parse("left")?,
+            event,
+            numwant,
+            compact: params.get("compact").map(Vec::as_slice) == Some(b"1"),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A peer of the swarm, as listed in announce responses
+pub struct AnnouncedPeer {
+    pub address: SocketAddr,
+    /// Unknown for peers which registered through the JSON protocol
+    pub peer_id: Option<PeerId>,
+}
+
+impl From<SocketAddr> for AnnouncedPeer {
+    /// Peers listed in compact form come without their peer ids
+    fn from(address: SocketAddr) -> Self {
+        Self {
+            address,
+            peer_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// Interval after which the peer should announce again, or it'll be dropped
+    pub interval: Duration,
+    /// Peers with the whole torrent
+    pub seeders: u32,
+    pub leechers: u32,
+    pub peers: Vec<AnnouncedPeer>,
+}
+
+impl AnnounceResponse {
+    /// Compact responses list IPv4 peers in `peers` and IPv6 peers in `peers6` (BEP 7), without
+    /// their peer ids
+    pub fn to_bencode(&self, compact: bool) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        if compact {
+            let peers: Vec<_> = self.peers.iter().map(|peer| peer.address).collect();
+            let (peers4, peers6) = compact_peers::encode(&peers);
+            dict.insert(b"peers".to_vec(), Value::from(peers4));
+            if !peers6.is_empty() {
+                dict.insert(b"peers6".to_vec(), Value::from(peers6));
+            }
+        } else {
+            let peers = self
+                .peers
+                .iter()
+                .map(|peer| {
+                    let mut dict = BTreeMap::new();
+                    dict.insert(
+                        b"ip".to_vec(),
+                        Value::from(peer.address.ip().to_string().as_str()),
+                    );
+                    dict.insert(b"port".to_vec(), Value::from(peer.address.port() as i64));
+                    if let Some(peer_id) = peer.peer_id {
+                        dict.insert(b"peer id".to_vec(), Value::from(peer_id.to_vec()));
+                    }
+                    Value::Dict(dict)
+                })
+                .collect();
+            dict.insert(b"peers".to_vec(), Value::List(peers));
+        }
+
+        dict.insert(
+            b"interval".to_vec(),
+            Value::from(self.interval.as_secs() as i64),
+        );
+        dict.insert(b"complete".to_vec(), Value::from(self.seeders as i64));
+        dict.insert(b"incomplete".to_vec(), Value::from(self.leechers as i64));
+        bencode::encode(&Value::Dict(dict))
+    }
+
+    /// Failure reasons sent by the tracker are returned as errors
+    pub fn from_bencode(data: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid announce response.");
+
+        let value = bencode::decode(data)?;
+        if let Some(reason) = value.get("failure reason") {
+            return Err(io::Error::other(format!(
+                "Tracker refused announce: {}",
+                reason.as_str().unwrap_or_default()
+            )));
+        }
+
+        let interval = value
+            .get("interval")
+            .and_then(Value::as_integer)
+            .and_then(|interval| u64::try_from(interval).ok())
+            .ok_or_else(invalid)?;
+        // Optional, not all trackers count peers
+        let count = |key| {
+            value
+                .get(key)
+                .and_then(Value::as_integer)
+                .and_then(|count| u32::try_from(count).ok())
+                .unwrap_or(0)
+        };
+        let parse_peer = |peer: &Value| {
+            let ip = peer
+                .get("ip")
+                .and_then(Value::as_str)
+                .and_then(|ip| IpAddr::from_str(ip).ok())
+                .ok_or_else(invalid)?;
+            let port = peer
+                .get("port")
+                .and_then(Value::as_integer)
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(invalid)?;
+            let peer_id = peer
+                .get("peer id")
+                .and_then(Value::as_bytes)
+                .and_then(|peer_id| peer_id.try_into().ok());
+            Ok(AnnouncedPeer {
+                address: SocketAddr::new(ip, port),
+                peer_id,
+            })
+        };
+        let mut peers: Vec<_> = match value.get("peers") {
+            Some(Value::List(peers)) => peers.iter().map(parse_peer).collect::<io::Result<_>>()?,
+            Some(Value::Bytes(peers)) => compact_peers::decode(peers, false)?
+                .into_iter()
+                .map(AnnouncedPeer::from)
+                .collect(),
+            _ => return Err(invalid()),
+        };
+        if let Some(peers6) = value.get("peers6").and_then(Value::as_bytes) {
+            peers.extend(
+                compact_peers::decode(peers6, true)?
+                    .into_iter()
+                    .map(AnnouncedPeer::from),
+            );
+        }
+
+        Ok(Self {
+            interval: Duration::from_secs(interval),
+            seeders: count("complete"),
+            leechers: count("incomplete"),
+            peers,
+        })
+    }
+}
+
+/// Bencoded response telling the client why its request failed
+pub fn failure_response(reason: &str) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    dict.insert(b"failure reason".to_vec(), Value::from(reason));
+    bencode::encode(&Value::Dict(dict))
+}
+
+#[derive(Debug, PartialEq)]
+/// Request line and headers of an HTTP request, everything servers read before responding
+pub struct RequestHead {
+    pub method: String,
+    /// Path and query, as sent
+    pub target: String,
+    /// Names as sent, `header` compares them ignoring case
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Reads the request line and headers from `reader`, up to the empty line ending them
+    ///
+    /// Fails with `InvalidData` if the request line is malformed, there are more than
+    /// `MAX_HTTP_HEADERS` headers or the head is longer than `MAX_HEAD_LEN`, or if the peer
+    /// disconnects or stays idle for `read_timeout` before the head ends
+    pub async fn read<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        read_timeout: Duration,
+    ) -> io::Result<Self> {
+        let request_line = read_head_line(reader, MAX_HEAD_LEN, read_timeout).await?;
+        Self::read_after_request_line(&request_line, reader, read_timeout).await
+    }
+
+    /// Like `read`, for a request whose `request_line` (`<method> <target> HTTP/1.x`) was
+    /// already read
+    pub async fn read_after_request_line<R: AsyncBufRead + Unpin>(
+        request_line: &str,
+        reader: &mut R,
+        read_timeout: Duration,
+    ) -> io::Result<Self> {
+        let mut head_left = MAX_HEAD_LEN
+            .checked_sub(request_line.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Request line too long."))?;
+        let mut headers = vec![];
+        loop {
+            let line = read_head_line(reader, head_left, read_timeout).await?;
+            if line.is_empty() {
+                break;
+            }
+            head_left -= line.len();
+            if headers.len() == MAX_HTTP_HEADERS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Too many headers.",
+                ));
+            }
+            // Malformed headers are ignored
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Malformed request line.",
+            ));
+        };
+        Ok(Self {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            headers,
+        })
+    }
+
+    /// The target without its query
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(&self.target, |(path, _)| path)
+    }
+
+    /// The target's query, empty if there's none
+    pub fn query(&self) -> &str {
+        self.target.split_once('?').map_or("", |(_, query)| query)
+    }
+
+    /// Value of the first header called `name`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a line of a request head with `read_line`, failing when the peer disconnects or
+/// stays idle for `read_timeout` first
+async fn read_head_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    read_timeout: Duration,
+) -> io::Result<String> {
+    match time::timeout(read_timeout, read_line(reader, max_len)).await {
+        Ok(Ok(Some(line))) => Ok(line),
+        Ok(Ok(None)) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Reads a line from `reader` without its line ending, or None at the end of the stream
+///
+/// Fails with `InvalidData` once the line is longer than `max_len` bytes, without buffering
+/// the rest of it
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    // Room for the line ending
+    (&mut *reader)
+        .take(max_len as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    if line.len() > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long."));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Wraps `body` in an HTTP response, after which the connection is closed
+pub fn http_response(body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Sends `request` to the tracker at `url` (`http://host:port/path`) and reads its response
+pub async fn announce(url: &str, request: &AnnounceRequest) -> io::Result<AnnounceResponse> {
+    AnnounceResponse::from_bencode(&get(url, &request.to_query()).await?)
+}
+
+/// Asks the tracker announcing at `url` for the stats of `info_hashes`' swarms
+///
+/// By convention, the scrape URL is the announce URL with `announce` replaced by `scrape`
+pub async fn scrape(url: &str, info_hashes: &[InfoHash]) -> io::Result<Vec<ScrapeStats>> {
+    let scrape_url = match url.rsplit_once('/') {
+        Some((base, last)) if last.starts_with("announce") => {
+            format!("{base}/{}", last.replacen("announce", "scrape", 1))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Tracker {url} doesn't support scraping."),
+            ))
+        }
+    };
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+    parse_scrape_response(&get(&scrape_url, &query).await?, info_hashes)
+}
+
+/// Parses the query of `GET /scrape`, which lists info-hashes as repeated `info_hash` parameters
+pub fn parse_scrape_query(query: &str) -> io::Result<Vec<InfoHash>> {
+    let info_hashes = query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("info_hash="))
+        .map(|info_hash| {
+            percent_decode(info_hash)?.try_into().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Parameter info_hash isn't 20 bytes long.",
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if info_hashes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Missing parameter info_hash.",
+        ));
+    }
+    Ok(info_hashes)
+}
+
+/// Bencoded response to `GET /scrape`, a `files` dictionary of every swarm's stats
+pub fn scrape_response(files: &[(InfoHash, ScrapeStats)]) -> Vec<u8> {
+    let files = files
+        .iter()
+        .map(|(info_hash, stats)| {
+            let mut dict = BTreeMap::new();
+            dict.insert(b"complete".to_vec(), Value::from(stats.seeders as i64));
+            dict.insert(b"downloaded".to_vec(), Value::from(stats.completed as i64));
+            dict.insert(b"incomplete".to_vec(), Value::from(stats.leechers as i64));
+            (info_hash.to_vec(), Value::Dict(dict))
+        })
+        .collect();
+
+    let mut dict = BTreeMap::new();
+    dict.insert(b"files".to_vec(), Value::Dict(files));
+    bencode::encode(&Value::Dict(dict))
+}
+
+/// Returns the stats of `info_hashes` from a scrape response, zeroed for those it doesn't list
+pub fn parse_scrape_response(
+    data: &[u8],
+    info_hashes: &[InfoHash],
+) -> io::Result<Vec<ScrapeStats>> {
+    let value = bencode::decode(data)?;
+    if let Some(reason) = value.get("failure reason") {
+        return Err(io::Error::other(format!(
+            "Tracker refused scrape: {}",
+            reason.as_str().unwrap_or_default()
+        )));
+    }
+    let Some(Value::Dict(files)) = value.get("files") else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid scrape response.",
+        ));
+    };
+
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            let Some(file) = files.get(info_hash.as_slice()) else {
+                return ScrapeStats::default();
+            };
+            let count = |key| {
+                file.get(key)
+                    .and_then(Value::as_integer)
+                    .and_then(|count| u32::try_from(count).ok())
+                    .unwrap_or(0)
+            };
+            ScrapeStats {
+                seeders: count("complete"),
+                completed: count("downloaded"),
+                leechers: count("incomplete"),
+            }
+        })
+        .collect())
+}
+
+/// Sends a GET request for `url` with `query`, returning the response body
+async fn get(url: &str, query: &str) -> io::Result<Vec<u8>> {
+    let target = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not an HTTP tracker URL: {url}."),
+        )
+    })?;
+    let (host, path) = match target.find('/') {
+        Some(i) => target.split_at(i),
+        None => (target, "/"),
+    };
+
+    let mut stream = TcpStream::connect(host).await?;
+    stream
+        .write_all(format!("GET {path}?{query} HTTP/1.0\r\nHost: {host}\r\n\r\n").as_bytes())
+        .await?;
+
+    // The tracker closes the connection after its response
+    let mut response = vec![];
+    stream
+        .take(MAX_RESPONSE_LEN)
+        .read_to_end(&mut response)
+        .await?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response.");
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let status_line = response.split(|byte| *byte == b'\r').next().unwrap();
+    if status_line.split(|byte| *byte == b' ').nth(1) != Some(b"200") {
+        return Err(io::Error::other(format!(
+            "Tracker responded with {}.",
+            String::from_utf8_lossy(status_line)
+        )));
+    }
+
+    Ok(response.split_off(header_end + 4))
+}
+
+/// Encodes every byte but unreserved characters as `%XX`
+fn percent_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+pub fn percent_decode(value: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid percent-encoding.");
+
+    let mut bytes = vec![];
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [
+                chars.next().ok_or_else(invalid)?,
+                chars.next().ok_or_else(invalid)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [0x12; 20],
+            peer_id: *b"-PG0011-abcdefghijk%",
+            ip: None,
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: Some(AnnounceEvent::Started),
+            numwant: None,
+            compact: false,
+        }
+    }
+
+    #[test]
+    fn percent_encoding_roundtrip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = percent_encode(&bytes);
+        assert!(encoded.is_ascii());
+        assert_eq!(percent_decode(&encoded).unwrap(), bytes);
+
+        assert_eq!(percent_encode(b"a b~"), "a%20b~");
+        assert!(percent_decode("%4").is_err());
+        assert!(percent_decode("%zz").is_err());
+    }
+
+    #[test]
+    fn AnnounceRequest_query_roundtrip() {
+        let mut request = request();
+        assert_eq!(
+            AnnounceRequest::from_query(&request.to_query()).unwrap(),
+            request
+        );
+
+        request.ip = Some(IpAddr::from_str("127.0.0.2").unwrap());
+        request.event = None;
+        request.numwant = Some(80);
+        request.compact = true;
+        assert_eq!(
+            AnnounceRequest::from_query(&request.to_query()).unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn AnnounceRequest_from_standard_query() {
+        // As sent by other clients, with parameters in another order and some unsupported ones
+        let query = "info_hash=%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12%12\
+            &peer_id=-PG0011-abcdefghijk%25&port=6881&uploaded=1&downloaded=2&left=3\
+            &corrupt=0&key=abc&event=started&numwant=80&compact=1&no_peer_id=1";
+        assert_eq!(
+            AnnounceRequest::from_query(query).unwrap(),
+            AnnounceRequest {
+                numwant: Some(80),
+                compact: true,
+                ..request()
+            }
+        );
+    }
+
+    #[test]
+    fn AnnounceRequest_rejects_invalid_queries() {
+        let query = request().to_query();
+        assert!(AnnounceRequest::from_query(&query.replace("port=6881", "port=70000")).is_err());
+        assert!(AnnounceRequest::from_query(&query.replace("event=started", "event=x")).is_err());
+        assert!(
+            AnnounceRequest::from_query(&query.replace("info_hash=%12", "info_hash=")).is_err()
+        );
+        assert!(AnnounceRequest::from_query(&(query.clone() + "&numwant=x")).is_err());
+        assert!(AnnounceRequest::from_query("port=1").is_err());
+    }
+
+    #[test]
+    fn AnnounceResponse_bencode_roundtrip() {
+        let response = AnnounceResponse {
+            interval: Duration::from_secs(60),
+            seeders: 1,
+            leechers: 2,
+            peers: vec![
+                AnnouncedPeer {
+                    address: SocketAddr::from_str("127.0.0.1:6881").unwrap(),
+                    peer_id: Some([7; 20]),
+                },
+                AnnouncedPeer {
+                    address: SocketAddr::from_str("[::1]:6882").unwrap(),
+                    peer_id: None,
+                },
+            ],
+        };
+        let encoded = response.to_bencode(false);
+        assert!(encoded
+            .starts_with(b"d8:completei1e10:incompletei2e8:intervali60e5:peersld2:ip9:127.0.0.1"));
+        assert_eq!(AnnounceResponse::from_bencode(&encoded).unwrap(), response);
+    }
+
+    #[test]
+    fn AnnounceResponse_compact_roundtrip() {
+        let mut response = AnnounceResponse {
+            interval: Duration::from_secs(60),
+            seeders: 0,
+            leechers: 2,
+            peers: vec![
+                AnnouncedPeer::from(SocketAddr::from_str("127.0.0.1:6881").unwrap()),
+                AnnouncedPeer::from(SocketAddr::from_str("[::1]:6882").unwrap()),
+            ],
+        };
+        let encoded = response.to_bencode(true);
+        let peers = [127, 0, 0, 1, 0x1a, 0xe1];
+        let expected = [b"5:peers6:".as_slice(), &peers, b"6:peers618:"].concat();
+        assert!(encoded
+            .windows(expected.len())
+            .any(|window| window == expected));
+        assert_eq!(AnnounceResponse::from_bencode(&encoded).unwrap(), response);
+
+        // Peer ids are dropped, and `peers6` is left out without IPv6 peers
+        response.peers = vec![AnnouncedPeer {
+            address: SocketAddr::from_str("127.0.0.1:6881").unwrap(),
+            peer_id: Some([7; 20]),
+        }];
+        let encoded = response.to_bencode(true);
+        assert!(!encoded.windows(8).any(|window| window == b"6:peers6"));
+        assert_eq!(
+            AnnounceResponse::from_bencode(&encoded).unwrap().peers,
+            vec![AnnouncedPeer::from(response.peers[0].address)]
+        );
+    }
+
+    #[test]
+    fn AnnounceResponse_failure_is_error() {
+        let err = AnnounceResponse::from_bencode(&failure_response("Go away.")).unwrap_err();
+        assert!(err.to_string().contains("Go away."));
+    }
+
+    #[test]
+    fn scrape_roundtrip() {
+        let query = format!(
+            "info_hash={}&info_hash={}&ignored=1",
+            percent_encode(&[1; 20]),
+            percent_encode(&[2; 20])
+        );
+        assert_eq!(parse_scrape_query(&query).unwrap(), vec![[1; 20], [2; 20]]);
+        assert!(parse_scrape_query("").is_err());
+        assert!(parse_scrape_query("info_hash=%01").is_err());
+
+        let stats = ScrapeStats {
+            seeders: 1,
+            completed: 2,
+            leechers: 3,
+        };
+        let encoded = scrape_response(&[([1; 20], stats)]);
+        assert!(encoded.ends_with(b"d8:completei1e10:downloadedi2e10:incompletei3eeee"));
+        assert_eq!(
+            parse_scrape_response(&encoded, &[[1; 20], [2; 20]]).unwrap(),
+            vec![stats, ScrapeStats::default()]
+        );
+        assert!(parse_scrape_response(&failure_response("No."), &[[1; 20]]).is_err());
+    }
+
+    async fn read_head(request_line: &str, headers: &[u8]) -> io::Result<RequestHead> {
+        let mut reader = io::BufReader::new(headers);
+        RequestHead::read_after_request_line(request_line, &mut reader, Duration::from_secs(1))
+            .await
+    }
+
+    #[tokio::test]
+    async fn RequestHead_read() {
+        let head = read_head(
+            "GET /announce?a=1&b=2 HTTP/1.1",
+            b"Host: tracker\r\nrange:  bytes=0-1\r\nmalformed\r\n\r\nbody",
+        )
+        .await
+        .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path(), "/announce");
+        assert_eq!(head.query(), "a=1&b=2");
+        assert_eq!(head.header("Range"), Some("bytes=0-1"));
+        assert_eq!(head.headers.len(), 2);
+
+        let head = read_head("HEAD / HTTP/1.0", b"\r\n").await.unwrap();
+        assert_eq!((head.path(), head.query()), ("/", ""));
+
+        let err = read_head("GET", b"\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_head("GET / HTTP/1.1", b"Host: tracker\r\n")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let headers = "A: b\r\n".repeat(MAX_HTTP_HEADERS + 1);
+        let err = read_head("GET / HTTP/1.1", headers.as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn RequestHead_read_rejects_oversized_heads() {
+        let head = RequestHead::read(
+            &mut io::BufReader::new(&b"GET / HTTP/1.1\r\nA: b\r\n\r\n"[..]),
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(head.header("a"), Some("b"));
+
+        // A line which never ends, which would otherwise be buffered until memory runs out
+        let mut endless = io::BufReader::new(io::repeat(b'x'));
+        let err = RequestHead::read(&mut endless, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Headers short on their own, but too long together
+        let header = format!("A: {}\r\n", "x".repeat(MAX_HEAD_LEN / 4));
+        let err = read_head("GET / HTTP/1.1", header.repeat(4).as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
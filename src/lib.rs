@@ -0,0 +1,19 @@
+pub mod bencode;
+pub mod choker;
+pub mod client;
+pub mod compact_peers;
+pub mod framing;
+pub mod http_tracker;
+pub mod metainfo;
+pub mod packet_hash;
+pub mod peer_wire;
+pub mod piece_picker;
+pub mod rate_limit;
+pub mod requests;
+pub mod scheduler;
+pub mod storage;
+pub mod stream_server;
+pub mod torrent_file;
+pub mod tracker;
+pub mod tracker_tiers;
+pub mod udp_tracker;
@@ -1,39 +1,42 @@
+use std::env;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use client::Client;
-use tracker::Tracker;
-use tokio::fs::OpenOptions;
+use playground::client::Client;
+use playground::metainfo::{to_hex, Metainfo};
+use playground::torrent_file::TorrentFile;
+use playground::tracker::Tracker;
+use tokio::io;
 use tokio::sync::oneshot;
 use tokio::time::{self, sleep};
-use torrent_file::TorrentFile;
 
-mod client;
-mod requests;
-mod tracker;
-mod torrent_file;
+const DEFAULT_PACKET_SIZE: usize = 1024;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let packet_size = 1024;
-    let torrent_size = {
-        let complete_torrent_file = OpenOptions::new()
-            .read(true)
-            .open(".testfiles/sent.png")
-            .await?;
-        complete_torrent_file.metadata().await.unwrap().len() as usize
-    };
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("create") {
+        return create_torrent(&args[2..]).await;
+    }
 
+    let packet_size = DEFAULT_PACKET_SIZE;
     let server_addr = SocketAddr::from_str("127.0.0.168:1111").unwrap();
+    let metainfo = Metainfo::create(
+        ".testfiles/sent.png",
+        packet_size,
+        &format!("tcp://{server_addr}"),
+    )
+    .await?;
+
     let seed1_addr = SocketAddr::from_str("127.0.0.166:2222").unwrap();
     let seed2_addr = SocketAddr::from_str("127.0.0.167:3333").unwrap();
     let leech_addr = SocketAddr::from_str("127.0.0.167:4444").unwrap();
     let full_peer_addr = SocketAddr::from_str("127.0.0.167:5555").unwrap();
 
     let (tracker_wx, tracker_rx) = oneshot::channel::<()>();
-    let mut server = Tracker::new();
+    let server = Tracker::new();
     let server_handle = tokio::spawn(async move { server.listen(&server_addr, tracker_rx).await });
 
     // Otherwise might not bind before the client attempts connecting to the server
@@ -43,13 +46,14 @@ async fn main() -> std::io::Result<()> {
     let (seed_wx, seed_rx) = oneshot::channel::<()>();
     let seed = Client::new(
         seed1_addr,
+        metainfo.info_hash(),
         TorrentFile::from_complete(".testfiles/sent.png", packet_size).unwrap(),
-    );
+    )
+    .with_trackers(metainfo.announce_list());
 
     let seed_handle = tokio::spawn({
         async move {
             tokio::time::sleep(Duration::from_millis(50)).await;
-            seed.register_as_peer(&server_addr).await?;
             seed.seed_loop(seed_rx).await
         }
     });
@@ -58,13 +62,14 @@ async fn main() -> std::io::Result<()> {
     let (seed2_wx, seed2_rx) = oneshot::channel::<()>();
     let seed = Client::new(
         seed2_addr,
+        metainfo.info_hash(),
         TorrentFile::from_complete(".testfiles/sent.png", packet_size).unwrap(),
-    );
+    )
+    .with_trackers(metainfo.announce_list());
 
     let seed2_handle = tokio::spawn({
         async move {
             tokio::time::sleep(Duration::from_millis(50)).await;
-            seed.register_as_peer(&server_addr).await?;
             seed.seed_loop(seed2_rx).await
         }
     });
@@ -73,49 +78,79 @@ async fn main() -> std::io::Result<()> {
 
     // Full peer (seed + leech) //
     let (_f_leech_wx, f_leech_rx) = oneshot::channel::<()>(); // writer unused but not dropped
-    let leech2 = Client::new(
-        full_peer_addr,
-        TorrentFile::new(".testfiles/received2.png", torrent_size, packet_size).unwrap(),
-    );
+    let leech2 =
+        Client::from_metainfo(full_peer_addr, ".testfiles/received2.png", &metainfo).unwrap();
 
     let peer_arc = Arc::new(leech2);
     let arc_copy = Arc::clone(&peer_arc);
-    let f_peer_leech_handle =
-        tokio::spawn(async move { arc_copy.leech_loop(&server_addr, f_leech_rx).await });
+    let f_peer_leech_handle = tokio::spawn(async move { arc_copy.leech_loop(f_leech_rx).await });
 
     let arc_copy = Arc::clone(&peer_arc);
     let (fseed_wx, fseed_rx) = oneshot::channel::<()>(); // writer unused but not dropped
-    let f_peer_seed_handle = tokio::spawn(async move {
-        arc_copy.register_as_peer(&server_addr).await?;
-        arc_copy.seed_loop(fseed_rx).await
-    });
+    let f_peer_seed_handle = tokio::spawn(async move { arc_copy.seed_loop(fseed_rx).await });
 
     // Let's give the leech+seed peer time to get some packets, so that it can send them to the leech
     time::sleep(Duration::from_millis(25)).await;
 
     // Leech //
     let (_leech_wx, leech_rx) = oneshot::channel::<()>(); // writer unused but not dropped
-    let leech = Client::new(
-        leech_addr,
-        TorrentFile::new(".testfiles/received.png", torrent_size, packet_size).unwrap(),
-    );
-    let leech_handle = tokio::spawn(async move { leech.leech_loop(&server_addr, leech_rx).await });
+    let leech = Client::from_metainfo(leech_addr, ".testfiles/received.png", &metainfo).unwrap();
+    let leech_handle = tokio::spawn(async move { leech.leech_loop(leech_rx).await });
 
     leech_handle.await??;
     f_peer_leech_handle.await??;
-    
-    tracker_wx.send(()).unwrap();
+
+    let stats = peer_arc.request_stats(&server_addr).await?;
+    println!(
+        "Swarm: {} seeders, {} leechers, {} completed downloads",
+        stats.seeders, stats.leechers, stats.completed
+    );
+
+    // Seeds deregister on shutdown, so the tracker has to outlive them
     seed_wx.send(()).unwrap();
     seed2_wx.send(()).unwrap();
     fseed_wx.send(()).unwrap();
-    
+
     f_peer_seed_handle.await??;
     seed_handle.await??;
     seed2_handle.await??;
+
+    tracker_wx.send(()).unwrap();
     server_handle.await??;
 
     Ok(())
 }
 
+/// `create <path> <announce> [packet_size] [output]`
+///
+/// Hashes a complete file and writes its metainfo to `output` (`<path>.torrent` by default)
+async fn create_torrent(args: &[String]) -> io::Result<()> {
+    let (path, announce) = match args {
+        [path, announce, ..] => (path, announce),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: create <path> <announce> [packet_size] [output]",
+            ))
+        }
+    };
+    let packet_size = match args.get(2) {
+        Some(packet_size) => packet_size
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+        None => DEFAULT_PACKET_SIZE,
+    };
+    let output = args
+        .get(3)
+        .cloned()
+        .unwrap_or_else(|| format!("{path}.torrent"));
+
+    let metainfo = Metainfo::create(path, packet_size, announce).await?;
+    metainfo.save_to_file(&output).await?;
+    println!("{output}: info-hash {}", to_hex(&metainfo.info_hash()));
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {}
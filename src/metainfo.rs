@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+use tokio::fs;
+use tokio::io;
+
+use crate::bencode::{self, Value};
+use crate::packet_hash::{HashAlgorithm, PacketHashes};
+use crate::storage::{scan_directory, FileEntry, OpenMode, Storage};
+
+/// SHA-1 of the bencoded `info` dictionary, identifies a torrent
+pub type InfoHash = [u8; 20];
+
+/// Formats `bytes` as lowercase hex, e.g. for printing info-hashes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Contents of a .torrent file
+pub struct Metainfo {
+    announce: String,
+    info: Info,
+    /// Top-level keys this implementation doesn't use, kept so that files round-trip
+    extra: BTreeMap<Vec<u8>, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The `info` dictionary of a .torrent file
+pub struct Info {
+    name: String,
+    piece_length: usize,
+    pieces: PacketHashes,
+    /// Total length, the sum of `files`' lengths for multi-file torrents
+    length: usize,
+    /// Files of a multi-file torrent, `None` for a single-file one
+    files: Option<Vec<FileEntry>>,
+    /// `info` keys this implementation doesn't use (e.g. `private`), needed for a correct info-hash
+    extra: BTreeMap<Vec<u8>, Value>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+impl Metainfo {
+    /// `pieces` have to be SHA-1 hashes, as that's what the format mandates
+    pub fn new(
+        announce: &str,
+        name: &str,
+        piece_length: usize,
+        pieces: PacketHashes,
+        length: usize,
+    ) -> io::Result<Self> {
+        Self::with_files(announce, name, piece_length, pieces, length, None)
+    }
+
+    /// Multi-file variant of `new`, `name` becomes the name of the directory containing `files`
+    pub fn new_multi_file(
+        announce: &str,
+        name: &str,
+        piece_length: usize,
+        pieces: PacketHashes,
+        files: Vec<FileEntry>,
+    ) -> io::Result<Self> {
+        let length = files.iter().map(|entry| entry.length).sum();
+        Self::with_files(announce, name, piece_length, pieces, length, Some(files))
+    }
+
+    fn with_files(
+        announce: &str,
+        name: &str,
+        piece_length: usize,
+        pieces: PacketHashes,
+        length: usize,
+        files: Option<Vec<FileEntry>>,
+    ) -> io::Result<Self> {
+        if pieces.algorithm() != HashAlgorithm::Sha1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Metainfo piece hashes have to be SHA-1.".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            announce: announce.to_owned(),
+            info: Info {
+                name: name.to_owned(),
+                piece_length,
+                pieces,
+                length,
+                files,
+                extra: BTreeMap::new(),
+            },
+            extra: BTreeMap::new(),
+        })
+    }
+
+    /// Describes the complete file (or directory) at `path` as a torrent, hashing it in `piece_length`-sized pieces
+    pub async fn create(path: &str, piece_length: usize, announce: &str) -> io::Result<Self> {
+        if piece_length == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Piece length can't be 0.".to_owned(),
+            ));
+        }
+
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid path {path}."))
+            })?;
+
+        let (files, length) = if Path::new(path).is_dir() {
+            let files = scan_directory(path)?;
+            let length = files.iter().map(|entry| entry.length).sum();
+            (Some(files), length)
+        } else {
+            (None, fs::metadata(path).await?.len() as usize)
+        };
+
+        // Pieces span file boundaries in multi-file torrents, `Storage` takes care of that
+        let mut storage = Storage::open(path, files.as_deref(), length, OpenMode::ReadOnly)?;
+        let mut pieces = vec![];
+        let mut buf = vec![0u8; piece_length];
+        for offset in (0..length).step_by(piece_length) {
+            let piece = &mut buf[..piece_length.min(length - offset)];
+            storage.read_at(offset, piece).await?;
+            pieces.push(HashAlgorithm::Sha1.digest(piece));
+        }
+
+        let pieces = PacketHashes::new(HashAlgorithm::Sha1, pieces);
+        Self::with_files(announce, name, piece_length, pieces, length, files)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut extra = match bencode::decode(data)? {
+            Value::Dict(dict) => dict,
+            _ => return Err(invalid_data("Metainfo is not a dictionary.")),
+        };
+
+        let announce = extra
+            .remove(b"announce".as_slice())
+            .as_ref()
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid_data("Missing or invalid `announce`."))?
+            .to_owned();
+        let info = match extra.remove(b"info".as_slice()) {
+            Some(Value::Dict(info)) => Info::from_dict(info)?,
+            _ => return Err(invalid_data("Missing or invalid `info`.")),
+        };
+
+        Ok(Self {
+            announce,
+            info,
+            extra,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = self.extra.clone();
+        dict.insert(b"announce".to_vec(), self.announce.as_str().into());
+        dict.insert(b"info".to_vec(), self.info.to_value());
+
+        bencode::encode(&Value::Dict(dict))
+    }
+
+    pub async fn from_file(path: &str) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path).await?)
+    }
+
+    pub async fn save_to_file(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_bytes()).await
+    }
+
+    pub fn announce(&self) -> &str {
+        &self.announce
+    }
+
+    /// Tracker tiers from `announce-list` (BEP 12), or just `announce` if there's no valid list
+    pub fn announce_list(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .extra
+            .get(b"announce-list".as_slice())
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Value::as_list)
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        match tiers.is_empty() {
+            true => vec![vec![self.announce.clone()]],
+            false => tiers,
+        }
+    }
+
+    /// Sets the tracker tiers, `announce` is kept for clients which don't support them
+    pub fn with_announce_list(mut self, tiers: &[Vec<String>]) -> Self {
+        let tiers = tiers
+            .iter()
+            .map(|tier| Value::List(tier.iter().map(|url| url.as_str().into()).collect()))
+            .collect();
+        self.extra
+            .insert(b"announce-list".to_vec(), Value::List(tiers));
+        self
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        Sha1::digest(bencode::encode(&self.info.to_value())).into()
+    }
+}
+
+impl Info {
+    fn from_dict(mut extra: BTreeMap<Vec<u8>, Value>) -> io::Result<Self> {
+        fn take_usize(dict: &mut BTreeMap<Vec<u8>, Value>, key: &str) -> io::Result<usize> {
+            dict.remove(key.as_bytes())
+                .as_ref()
+                .and_then(Value::as_integer)
+                .and_then(|i| usize::try_from(i).ok())
+                .ok_or_else(|| invalid_data(&format!("Missing or invalid `{key}`.")))
+        }
+        let piece_length = take_usize(&mut extra, "piece length")?;
+        if piece_length == 0 {
+            return Err(invalid_data("`piece length` can't be 0."));
+        }
+
+        // `files` is parsed but kept in `extra` as is, so that per-file keys this implementation
+        // doesn't know about still end up in the info-hash
+        let (files, length) = match extra.get(b"files".as_slice()) {
+            Some(files) => {
+                let files = Self::files_from_value(files)?;
+                let length = files
+                    .iter()
+                    .try_fold(0usize, |length, entry| length.checked_add(entry.length))
+                    .ok_or_else(|| invalid_data("Total length of `files` is too large."))?;
+                (Some(files), length)
+            }
+            None => (None, take_usize(&mut extra, "length")?),
+        };
+
+        let name = extra
+            .remove(b"name".as_slice())
+            .as_ref()
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid_data("Missing or invalid `name`."))?
+            .to_owned();
+
+        let digest_len = HashAlgorithm::Sha1.digest_len();
+        let pieces: Vec<Vec<u8>> = match extra.remove(b"pieces".as_slice()) {
+            Some(Value::Bytes(pieces)) if pieces.len() % digest_len == 0 => {
+                pieces.chunks(digest_len).map(<[u8]>::to_vec).collect()
+            }
+            _ => return Err(invalid_data("Missing or invalid `pieces`.")),
+        };
+        // Checked before anything is opened for the torrent, so that a bad .torrent can't make
+        // its files get created (or truncated) for nothing
+        if pieces.len() != length.div_ceil(piece_length) {
+            return Err(invalid_data("`pieces` doesn't match the torrent's length."));
+        }
+
+        Ok(Self {
+            name,
+            piece_length,
+            pieces: PacketHashes::new(HashAlgorithm::Sha1, pieces),
+            length,
+            files,
+            extra,
+        })
+    }
+
+    fn files_from_value(files: &Value) -> io::Result<Vec<FileEntry>> {
+        let invalid_files = || invalid_data("Invalid `files`.");
+
+        files
+            .as_list()
+            .ok_or_else(invalid_files)?
+            .iter()
+            .map(|file| {
+                let length = file
+                    .get("length")
+                    .and_then(Value::as_integer)
+                    .and_then(|i| usize::try_from(i).ok())
+                    .ok_or_else(invalid_files)?;
+                let path = file
+                    .get("path")
+                    .and_then(Value::as_list)
+                    .ok_or_else(invalid_files)?
+                    .iter()
+                    .map(|component| component.as_str().ok_or_else(invalid_files))
+                    .collect::<io::Result<PathBuf>>()?;
+
+                let entry = FileEntry { path, length };
+                if !entry.is_path_safe() {
+                    return Err(invalid_data("Unsafe path in `files`."));
+                }
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    fn files_to_value(files: &[FileEntry]) -> Value {
+        Value::List(
+            files
+                .iter()
+                .map(|entry| {
+                    let path = entry
+                        .path
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy().as_ref().into())
+                        .collect();
+                    Value::Dict(BTreeMap::from([
+                        (b"length".to_vec(), Value::Integer(entry.length as i64)),
+                        (b"path".to_vec(), Value::List(path)),
+                    ]))
+                })
+                .collect(),
+        )
+    }
+
+    fn to_value(&self) -> Value {
+        let mut dict = self.extra.clone();
+        dict.insert(b"name".to_vec(), self.name.as_str().into());
+        dict.insert(
+            b"piece length".to_vec(),
+            Value::Integer(self.piece_length as i64),
+        );
+        dict.insert(b"pieces".to_vec(), self.pieces.hashes().concat().into());
+        match &self.files {
+            Some(files) => {
+                dict.entry(b"files".to_vec())
+                    .or_insert_with(|| Self::files_to_value(files));
+            }
+            None => {
+                dict.insert(b"length".to_vec(), Value::Integer(self.length as i64));
+            }
+        }
+
+        Value::Dict(dict)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    pub fn pieces(&self) -> &PacketHashes {
+        &self.pieces
+    }
+
+    /// Total size of the torrent's data in bytes
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn files(&self) -> Option<&[FileEntry]> {
+        self.files.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Metainfo {
+        let pieces = PacketHashes::compute(HashAlgorithm::Sha1, b"ABCDabcdXY", 4);
+        Metainfo::new("tcp://127.0.0.1:1111", "example", 4, pieces, 10).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let metainfo = example();
+        let bytes = metainfo.to_bytes();
+
+        assert_eq!(Metainfo::from_bytes(&bytes).unwrap(), metainfo);
+        assert_eq!(Metainfo::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn roundtrip_keeps_unknown_keys() {
+        let data = b"d8:announce4:test13:creation datei1e4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        assert_eq!(Metainfo::from_bytes(data).unwrap().to_bytes(), data);
+    }
+
+    #[test]
+    fn info_hash_of_info_dict() {
+        let data = b"d8:announce4:test4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let metainfo = Metainfo::from_bytes(data).unwrap();
+
+        assert_eq!(
+            to_hex(&metainfo.info_hash()),
+            to_hex(&Sha1::digest(&data[23..data.len() - 1]))
+        );
+    }
+
+    #[tokio::test]
+    async fn create_from_file() {
+        let filename = ".testfiles/Metainfo_create";
+        let content = "ABCDabcdXY".as_bytes();
+        fs::write(filename, content).await.unwrap();
+
+        let metainfo = Metainfo::create(filename, 4, "tcp://127.0.0.1:1111")
+            .await
+            .unwrap();
+
+        assert_eq!(metainfo.info().name(), "Metainfo_create");
+        assert_eq!(metainfo.info().length(), 10);
+        assert_eq!(
+            metainfo.info().pieces(),
+            &PacketHashes::compute(HashAlgorithm::Sha1, content, 4)
+        );
+    }
+
+    #[tokio::test]
+    async fn create_from_directory() {
+        let root = ".testfiles/Metainfo_create_from_directory";
+        fs::create_dir_all(format!("{root}/sub")).await.unwrap();
+        fs::write(format!("{root}/a"), "ABC").await.unwrap();
+        fs::write(format!("{root}/sub/b"), "Dabcd").await.unwrap();
+        fs::write(format!("{root}/sub/c"), "XY").await.unwrap();
+
+        let metainfo = Metainfo::create(root, 4, "tcp://127.0.0.1:1111")
+            .await
+            .unwrap();
+
+        assert_eq!(metainfo.info().length(), 10);
+        assert_eq!(metainfo.info().files().unwrap().len(), 3);
+        assert_eq!(
+            metainfo.info().pieces(),
+            &PacketHashes::compute(HashAlgorithm::Sha1, "ABCDabcdXY".as_bytes(), 4)
+        );
+        let parsed = Metainfo::from_bytes(&metainfo.to_bytes()).unwrap();
+        assert_eq!(parsed.info().files(), metainfo.info().files());
+        assert_eq!(parsed.info_hash(), metainfo.info_hash());
+    }
+
+    #[test]
+    fn multi_file_keeps_unknown_file_keys() {
+        let data = b"d8:announce4:test4:infod5:filesld6:lengthi1e6:md5sum1:x4:pathl3:dir1:aeee4:name1:d12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let metainfo = Metainfo::from_bytes(data).unwrap();
+
+        assert_eq!(metainfo.info().files().unwrap()[0].path, Path::new("dir/a"));
+        assert_eq!(metainfo.to_bytes(), data);
+    }
+
+    #[test]
+    fn rejects_unsafe_file_paths() {
+        let data = b"d8:announce4:test4:infod5:filesld6:lengthi1e4:pathl2:..1:aeee4:name1:d12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(Metainfo::from_bytes(data).is_err());
+    }
+
+    #[test]
+    fn announce_list_falls_back_to_announce() {
+        let metainfo = example();
+        assert_eq!(
+            metainfo.announce_list(),
+            vec![vec!["tcp://127.0.0.1:1111".to_owned()]]
+        );
+
+        let tiers = vec![
+            vec!["udp://a:1".to_owned(), "http://b:2/announce".to_owned()],
+            vec!["tcp://c:3".to_owned()],
+        ];
+        let metainfo = metainfo.with_announce_list(&tiers);
+        let parsed = Metainfo::from_bytes(&metainfo.to_bytes()).unwrap();
+        assert_eq!(parsed.announce_list(), tiers);
+        assert_eq!(parsed.info_hash(), example().info_hash());
+
+        // Empty tiers and invalid entries are skipped
+        let data = b"d8:announce4:test13:announce-listlle3:abcli1eee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let parsed = Metainfo::from_bytes(data).unwrap();
+        assert_eq!(parsed.announce_list(), vec![vec!["test".to_owned()]]);
+    }
+
+    #[test]
+    fn rejects_inconsistent_lengths() {
+        // 2 pieces of 1 byte, but a single hash
+        let data = b"d8:announce4:test4:infod6:lengthi2e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(Metainfo::from_bytes(data).is_err());
+
+        // Lengths of `files` add up to more than fits in usize
+        let file = "d6:lengthi9000000000000000000e4:pathl1:aee";
+        let data = format!(
+            "d8:announce4:test4:infod5:filesl{}e4:name1:d12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            file.repeat(3)
+        );
+        let err = Metainfo::from_bytes(data.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "Total length of `files` is too large.");
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        assert!(Metainfo::from_bytes(b"d8:announce4:teste").is_err());
+        assert!(Metainfo::from_bytes(b"d8:announce4:test4:infod4:name1:aee").is_err());
+        assert!(Metainfo::new(
+            "test",
+            "a",
+            4,
+            PacketHashes::compute(HashAlgorithm::Sha256, b"ABCD", 4),
+            4
+        )
+        .is_err());
+    }
+}
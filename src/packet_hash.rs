@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+/// Hash function used to verify packets' integrity
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// Length of a single digest in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Expected hashes of every packet of a torrent, in packet order
+pub struct PacketHashes {
+    algorithm: HashAlgorithm,
+    hashes: Vec<Vec<u8>>,
+}
+
+impl PacketHashes {
+    pub fn new(algorithm: HashAlgorithm, hashes: Vec<Vec<u8>>) -> Self {
+        Self { algorithm, hashes }
+    }
+
+    /// Hashes `data` split into `packet_size`-sized packets (the last one may be shorter)
+    pub fn compute(algorithm: HashAlgorithm, data: &[u8], packet_size: usize) -> Self {
+        let hashes = data
+            .chunks(packet_size)
+            .map(|packet| algorithm.digest(packet))
+            .collect();
+
+        Self { algorithm, hashes }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn hashes(&self) -> &[Vec<u8>] {
+        &self.hashes
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Checks whether `data` hashes to the expected value of packet `packet_index`
+    pub fn verify(&self, packet_index: usize, data: &[u8]) -> bool {
+        match self.hashes.get(packet_index) {
+            Some(expected) => self.algorithm.digest(data) == *expected,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_lengths() {
+        assert_eq!(HashAlgorithm::Sha1.digest(b"abc").len(), 20);
+        assert_eq!(HashAlgorithm::Sha256.digest(b"abc").len(), 32);
+    }
+
+    #[test]
+    fn compute_splits_into_packets() {
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, b"ABCDabcdXY", 4);
+        assert_eq!(hashes.len(), 3);
+        assert!(hashes.verify(0, b"ABCD"));
+        assert!(hashes.verify(1, b"abcd"));
+        assert!(hashes.verify(2, b"XY"));
+    }
+
+    #[test]
+    fn verify_rejects_corrupt_data() {
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha256, b"ABCDabcd", 4);
+        assert!(!hashes.verify(0, b"ABCE"));
+        assert!(!hashes.verify(1, b"ABCD"));
+        assert!(!hashes.verify(2, b"ABCD"));
+    }
+}
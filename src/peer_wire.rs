@@ -0,0 +1,356 @@
+use bit_vec::BitVec;
+use rand::Rng;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::framing::{write_frame, FrameReader};
+use crate::metainfo::InfoHash;
+
+/// Identifies a client in handshakes
+pub type PeerId = [u8; 20];
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+/// Size of the blocks packets are requested in, the one most clients use
+pub const BLOCK_LEN: usize = 16 * 1024;
+/// Requests for larger blocks are refused
+pub const MAX_BLOCK_LEN: usize = 128 * 1024;
+
+/// Longest message peers may send for a torrent of `packet_count` packets, a `Piece` with
+/// the largest block or a `Bitfield`, length prefix included
+pub fn max_message_len(packet_count: usize) -> usize {
+    (13 + MAX_BLOCK_LEN).max(5 + packet_count.div_ceil(8))
+}
+
+/// Azureus-style peer id: client tag and version, followed by random characters
+pub fn generate_peer_id() -> PeerId {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-PG0011-");
+    let mut rng = rand::thread_rng();
+    for byte in &mut peer_id[8..] {
+        *byte = rng.sample(rand::distributions::Alphanumeric);
+    }
+    peer_id
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// First message sent by both sides of a connection
+pub struct Handshake {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+impl Handshake {
+    /// No extensions are supported, so all reserved bytes are zero
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> io::Result<Self> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown protocol in handshake.",
+            ));
+        }
+
+        Ok(Self {
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..].try_into().unwrap(),
+        })
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.to_bytes()).await
+    }
+
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        reader.read_exact(&mut bytes).await?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Part of a packet, `length` bytes starting at `begin`
+pub struct BlockRequest {
+    pub index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Messages peers exchange after the handshake
+pub enum PeerMessage {
+    KeepAlive,
+    /// Requests won't be answered until an `Unchoke`
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// The peer has just finished downloading a packet
+    Have(usize),
+    /// Packets the peer has, sent right after the handshake
+    Bitfield(BitVec),
+    Request(BlockRequest),
+    Piece {
+        index: usize,
+        begin: usize,
+        block: Vec<u8>,
+    },
+    /// Withdraws a previous `Request`
+    Cancel(BlockRequest),
+    /// Messages of extensions the client doesn't support, which it ignores
+    Unknown(u8),
+}
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+
+impl PeerMessage {
+    /// Encodes the message as a frame body: message id followed by its payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_u32s(bytes: &mut Vec<u8>, values: &[usize]) {
+            for &value in values {
+                bytes.extend_from_slice(&(value as u32).to_be_bytes());
+            }
+        }
+
+        let mut bytes = vec![];
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => bytes.push(CHOKE),
+            PeerMessage::Unchoke => bytes.push(UNCHOKE),
+            PeerMessage::Interested => bytes.push(INTERESTED),
+            PeerMessage::NotInterested => bytes.push(NOT_INTERESTED),
+            PeerMessage::Have(index) => {
+                bytes.push(HAVE);
+                push_u32s(&mut bytes, &[*index]);
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                bytes.push(BITFIELD);
+                bytes.extend(bitfield.to_bytes());
+            }
+            PeerMessage::Request(request) => {
+                bytes.push(REQUEST);
+                push_u32s(&mut bytes, &[request.index, request.begin, request.length]);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                bytes.push(PIECE);
+                push_u32s(&mut bytes, &[*index, *begin]);
+                bytes.extend_from_slice(block);
+            }
+            PeerMessage::Cancel(request) => {
+                bytes.push(CANCEL);
+                push_u32s(&mut bytes, &[request.index, request.begin, request.length]);
+            }
+            PeerMessage::Unknown(id) => bytes.push(*id),
+        }
+        bytes
+    }
+
+    /// Decodes a frame body produced by `to_bytes`
+    ///
+    /// `Bitfield`s keep their padding bits, so they're a multiple of 8 long
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let Some((&id, payload)) = bytes.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid payload of message {id}."),
+            )
+        };
+        let u32_at = |i: usize| -> io::Result<usize> {
+            let bytes = payload.get(4 * i..4 * i + 4).ok_or_else(invalid)?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        let block_request = || -> io::Result<BlockRequest> {
+            if payload.len() != 12 {
+                return Err(invalid());
+            }
+            Ok(BlockRequest {
+                index: u32_at(0)?,
+                begin: u32_at(1)?,
+                length: u32_at(2)?,
+            })
+        };
+        let no_payload = |message: PeerMessage| match payload.is_empty() {
+            true => Ok(message),
+            false => Err(invalid()),
+        };
+
+        match id {
+            CHOKE => no_payload(PeerMessage::Choke),
+            UNCHOKE => no_payload(PeerMessage::Unchoke),
+            INTERESTED => no_payload(PeerMessage::Interested),
+            NOT_INTERESTED => no_payload(PeerMessage::NotInterested),
+            HAVE if payload.len() == 4 => Ok(PeerMessage::Have(u32_at(0)?)),
+            BITFIELD => Ok(PeerMessage::Bitfield(BitVec::from_bytes(payload))),
+            REQUEST => Ok(PeerMessage::Request(block_request()?)),
+            PIECE if payload.len() >= 8 => Ok(PeerMessage::Piece {
+                index: u32_at(0)?,
+                begin: u32_at(1)?,
+                block: payload[8..].to_vec(),
+            }),
+            CANCEL => Ok(PeerMessage::Cancel(block_request()?)),
+            HAVE | PIECE => Err(invalid()),
+            id => Ok(PeerMessage::Unknown(id)),
+        }
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_frame(writer, &self.to_bytes()).await
+    }
+
+    /// Returns `None` if the stream ended cleanly between messages
+    ///
+    /// Cancellation safe, as `FrameReader::next_frame`
+    pub async fn read_from<R>(frames: &mut FrameReader<R>) -> io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        match frames.next_frame().await? {
+            Some(body) => Self::from_bytes(&body).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    #[test]
+    fn Handshake_layout() {
+        let handshake = Handshake {
+            info_hash: [1; 20],
+            peer_id: *b"-PG0011-abcdefghijkl",
+        };
+        let bytes = handshake.to_bytes();
+
+        assert_eq!(bytes.len(), 68);
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], &[0; 8]);
+        assert_eq!(&bytes[28..48], &[1; 20]);
+        assert_eq!(&bytes[48..], b"-PG0011-abcdefghijkl");
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn Handshake_rejects_other_protocols() {
+        let mut bytes = Handshake {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+        }
+        .to_bytes();
+        bytes[1] = b'b';
+        assert!(Handshake::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn generate_peer_id_is_printable() {
+        let peer_id = generate_peer_id();
+        assert_eq!(&peer_id[..8], b"-PG0011-");
+        assert!(peer_id.iter().all(u8::is_ascii_graphic));
+        assert_ne!(peer_id, generate_peer_id());
+    }
+
+    #[test]
+    fn PeerMessage_encoding() {
+        let request = BlockRequest {
+            index: 1,
+            begin: 0x4000,
+            length: 0x4000,
+        };
+        let cases = [
+            (PeerMessage::KeepAlive, vec![]),
+            (PeerMessage::Choke, vec![0]),
+            (PeerMessage::Unchoke, vec![1]),
+            (PeerMessage::Interested, vec![2]),
+            (PeerMessage::NotInterested, vec![3]),
+            (PeerMessage::Have(258), vec![4, 0, 0, 1, 2]),
+            (
+                PeerMessage::Bitfield(BitVec::from_bytes(&[0b1010_0000])),
+                vec![5, 0b1010_0000],
+            ),
+            (
+                PeerMessage::Request(request),
+                vec![6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                PeerMessage::Piece {
+                    index: 1,
+                    begin: 2,
+                    block: b"ab".to_vec(),
+                },
+                vec![7, 0, 0, 0, 1, 0, 0, 0, 2, b'a', b'b'],
+            ),
+            (
+                PeerMessage::Cancel(request),
+                vec![8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (PeerMessage::Unknown(20), vec![20]),
+        ];
+
+        for (message, bytes) in cases {
+            assert_eq!(message.to_bytes(), bytes);
+            assert_eq!(PeerMessage::from_bytes(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn PeerMessage_rejects_malformed_payloads() {
+        assert!(PeerMessage::from_bytes(&[0, 1]).is_err());
+        assert!(PeerMessage::from_bytes(&[4, 0, 0, 1]).is_err());
+        assert!(PeerMessage::from_bytes(&[6, 0, 0, 0, 1]).is_err());
+        assert!(PeerMessage::from_bytes(&[7, 0, 0, 0, 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn PeerMessage_read_from_frames() {
+        let mut buf = vec![];
+        PeerMessage::Interested.write_to(&mut buf).await.unwrap();
+        PeerMessage::KeepAlive.write_to(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 2, 0, 0, 0, 0]);
+
+        let mut frames = FrameReader::new(buf.as_slice(), max_message_len(1));
+        assert_eq!(
+            PeerMessage::read_from(&mut frames).await.unwrap(),
+            Some(PeerMessage::Interested)
+        );
+        assert_eq!(
+            PeerMessage::read_from(&mut frames).await.unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+        assert_eq!(PeerMessage::read_from(&mut frames).await.unwrap(), None);
+    }
+}
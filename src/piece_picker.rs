@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::seq::SliceRandom;
+
+/// Decides the order in which packets get requested
+pub trait PiecePicker: Send + Sync {
+    /// Picks up to `count` of `candidates`, packets a peer has that are neither downloaded nor
+    /// requested yet, most wanted first
+    ///
+    /// `replicas[i]` is the number of connected peers which have packet `i`
+    fn pick(&self, candidates: Vec<usize>, replicas: &[usize], count: usize) -> Vec<usize>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Picks packets in index order
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&self, mut candidates: Vec<usize>, _replicas: &[usize], count: usize) -> Vec<usize> {
+        candidates.sort_unstable();
+        candidates.truncate(count);
+        candidates
+    }
+}
+
+#[derive(Debug, Default)]
+/// Picks packets in index order from the packet being streamed on, then the ones before it,
+/// so that data is available in the order it's consumed
+pub struct Streaming {
+    position: AtomicUsize,
+}
+
+impl Streaming {
+    pub fn position(&self) -> usize {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    /// Moves the priority to `packet_index`, e.g. when a stream seeks
+    pub fn set_position(&self, packet_index: usize) {
+        self.position.store(packet_index, Ordering::Relaxed);
+    }
+}
+
+impl PiecePicker for Streaming {
+    fn pick(&self, mut candidates: Vec<usize>, _replicas: &[usize], count: usize) -> Vec<usize> {
+        let position = self.position();
+        candidates.sort_unstable_by_key(|&i| (i < position, i));
+        candidates.truncate(count);
+        candidates
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Picks packets the fewest peers have first, so that they spread before their peers leave
+///
+/// Ties are broken randomly, so that leeches don't all ask for the same packets
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&self, mut candidates: Vec<usize>, replicas: &[usize], count: usize) -> Vec<usize> {
+        candidates.shuffle(&mut rand::thread_rng());
+        // Stable, so that the shuffled order breaks ties
+        candidates.sort_by_key(|&i| replicas.get(i).copied().unwrap_or(0));
+        candidates.truncate(count);
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    #[test]
+    fn Sequential_picks_in_order() {
+        assert_eq!(
+            Sequential.pick(vec![5, 1, 3], &[0, 9, 0, 1, 0, 0], 2),
+            vec![1, 3]
+        );
+    }
+
+    #[test]
+    fn Streaming_picks_from_position() {
+        let streaming = Streaming::default();
+        let candidates = vec![6, 0, 3, 4, 1];
+        assert_eq!(streaming.pick(candidates.clone(), &[], 3), vec![0, 1, 3]);
+
+        streaming.set_position(4);
+        assert_eq!(streaming.pick(candidates, &[], 4), vec![4, 6, 0, 1]);
+    }
+
+    #[test]
+    fn RarestFirst_picks_rarest() {
+        let replicas = [3, 1, 2, 1, 5];
+        for _ in 0..20 {
+            let picked = RarestFirst.pick(vec![0, 1, 2, 3, 4], &replicas, 3);
+            assert!(picked[..2] == [1, 3] || picked[..2] == [3, 1]);
+            assert_eq!(picked[2], 2);
+        }
+    }
+
+    #[test]
+    fn RarestFirst_breaks_ties_randomly() {
+        // 8 tied packets have 40320 orders, one of 20 picks differs unless ties are broken randomly
+        let replicas = [1; 8];
+        let first = RarestFirst.pick((0..8).collect(), &replicas, 8);
+        assert!((0..20).any(|_| RarestFirst.pick((0..8).collect(), &replicas, 8) != first));
+    }
+}
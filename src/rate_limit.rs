@@ -0,0 +1,167 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+struct Bucket {
+    /// Bytes per second, unlimited if None (or 0)
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket limiting the bytes transferred per second, with bursts of up to a second's worth
+///
+/// The rate can be changed while transfers wait for it
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    /// Wakes waiting transfers when the rate changes
+    rate_changed: Notify,
+}
+
+impl RateLimiter {
+    /// Starts with an empty bucket, so that the limit applies from the first byte
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            rate_changed: Notify::new(),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Sets the limit in bytes per second, None removes it
+    ///
+    /// Tokens gathered beyond the new rate are dropped, limiting an unlimited bucket empties it
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or(0) as f64);
+        bucket.last_refill = Instant::now();
+        drop(bucket);
+        self.rate_changed.notify_waiters();
+    }
+
+    /// Waits until `bytes` may be transferred
+    pub async fn acquire(&self, bytes: usize) {
+        loop {
+            let rate_changed = self.rate_changed.notified();
+            let Some(wait) = self.try_acquire(bytes) else {
+                return;
+            };
+            select! {
+                _ = time::sleep(wait) => {}
+                _ = rate_changed => {}
+            }
+        }
+    }
+
+    /// Takes `bytes` tokens if there are enough, or returns how long until there are
+    fn try_acquire(&self, bytes: usize) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let rate = bucket.rate.filter(|&rate| rate > 0)? as f64;
+        let bytes = bytes as f64;
+
+        // Transfers larger than the bucket pass once it's full
+        let capacity = rate.max(bytes);
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= bytes {
+            bucket.tokens -= bytes;
+            return None;
+        }
+        Some(Duration::from_secs_f64((bytes - bucket.tokens) / rate))
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+#[derive(Clone, Default)]
+/// Limits of a single torrent, and optionally limits shared by every torrent of a process
+pub struct RateLimits {
+    pub torrent: Arc<RateLimiter>,
+    pub global: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    /// Waits until `bytes` may be transferred under both limits
+    pub async fn acquire(&self, bytes: usize) {
+        self.torrent.acquire(bytes).await;
+        if let Some(global) = &self.global {
+            global.acquire(bytes).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn RateLimiter_limits_rate() {
+        let limiter = RateLimiter::new(Some(50_000));
+        let start = Instant::now();
+
+        // The bucket starts empty
+        limiter.acquire(50_000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        limiter.acquire(25_000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+
+        // Idle time fills the bucket, but only up to a second's worth
+        time::sleep(Duration::from_secs(5)).await;
+        let start = Instant::now();
+        limiter.acquire(50_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(10_000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn RateLimiter_passes_transfers_larger_than_bucket() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let start = Instant::now();
+        limiter.acquire(120_000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn RateLimiter_rate_changes_wake_waiting_transfers() {
+        let limiter = Arc::new(RateLimiter::new(Some(1)));
+        let waiting = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+            async move { limiter.acquire(1000).await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        limiter.set_rate(None);
+        time::timeout(Duration::from_millis(100), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(limiter.rate(), None);
+    }
+}
@@ -1,32 +1,45 @@
-use bit_vec::BitVec;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::metainfo::InfoHash;
 
 #[derive(Serialize, Deserialize)]
-/// Requests peers send to a tracker
+/// Requests peers send to a tracker, each concerning the swarm of a single torrent
 pub enum RequestToTracker {
-    GetPeers,
-    RegisterAsPeer(SocketAddr),
+    /// Asks for up to `numwant` random peers, or the tracker's default number if `None`
+    GetPeers(InfoHash, Option<u32>),
+    /// Registers a new peer or refreshes an already registered one, along with the bytes it still
+    /// has to download, then the bytes it uploaded and downloaded so far
+    RegisterAsPeer(InfoHash, SocketAddr, u64, u64, u64),
+    /// Sent by a peer leaving the swarm ("stopped" announce)
+    Deregister(InfoHash, SocketAddr),
+    /// Sent by a peer which finished downloading ("completed" announce)
+    ReportCompleted(InfoHash, SocketAddr),
+    /// Asks for the health of the given torrents' swarms
+    Scrape(Vec<InfoHash>),
 }
 
 #[derive(Serialize, Deserialize)]
 /// Tracker's response to a peer's request (`RequestToTracker`)
 pub enum TrackerResponse {
-    Peers(Vec<SocketAddr>),
+    /// Peers are sent in compact form, hex-encoded
+    Peers(#[serde(with = "crate::compact_peers::hex")] Vec<SocketAddr>),
     InvalidRequest,
-    RegisteredSuccesfully,
+    /// Contains the interval after which the peer should register again, or it'll be dropped
+    RegisteredSuccesfully(Duration),
+    Deregistered,
+    CompletionRecorded,
+    /// Statistics of the scraped swarms, in the order they were asked for
+    Stats(Vec<ScrapeStats>),
 }
 
-#[derive(Serialize, Deserialize)]
-/// Requests a leech makes to its peers
-pub enum LeechRequest {
-    GetAvailability,
-    GetPackets(usize, usize),
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-/// Seed's response to leech's request (`LeechRequest`)
-pub enum SeedResponse {
-    InvalidRequest,
-    Availability(BitVec),
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Health of a torrent's swarm
+pub struct ScrapeStats {
+    /// Peers with the whole torrent
+    pub seeders: u32,
+    /// Downloads the tracker saw finishing
+    pub completed: u32,
+    pub leechers: u32,
 }
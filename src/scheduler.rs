@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use bit_vec::BitVec;
+
+use crate::piece_picker::{PiecePicker, Sequential};
+
+/// Decides which packets to request from which peer, so that no packet is requested twice
+/// until the endgame
+pub struct Scheduler {
+    /// Packets already downloaded
+    downloaded: BitVec,
+    /// Number of peers each packet is currently requested from
+    requested: Vec<usize>,
+    /// Number of connected peers having each packet
+    replicas: Vec<usize>,
+    picker: Arc<dyn PiecePicker>,
+}
+
+impl Scheduler {
+    /// `downloaded` is the availability of packets before the download starts
+    ///
+    /// Packets are picked in index order, unless another picker is set with `with_picker`
+    pub fn new(downloaded: BitVec) -> Self {
+        Self {
+            requested: vec![0; downloaded.len()],
+            replicas: vec![0; downloaded.len()],
+            downloaded,
+            picker: Arc::new(Sequential),
+        }
+    }
+
+    pub fn with_picker(mut self, picker: Arc<dyn PiecePicker>) -> Self {
+        self.picker = picker;
+        self
+    }
+
+    /// Counts the packets of a newly connected peer (or its new bitfield)
+    pub fn add_peer(&mut self, peer_availability: &BitVec) {
+        for (i, has) in peer_availability
+            .iter()
+            .enumerate()
+            .take(self.replicas.len())
+        {
+            self.replicas[i] += has as usize;
+        }
+    }
+
+    /// Stops counting the packets of a disconnected peer
+    pub fn remove_peer(&mut self, peer_availability: &BitVec) {
+        for (i, has) in peer_availability
+            .iter()
+            .enumerate()
+            .take(self.replicas.len())
+        {
+            self.replicas[i] -= has as usize;
+        }
+    }
+
+    /// Counts a packet a connected peer announced it has since
+    pub fn add_replica(&mut self, packet_index: usize) {
+        self.replicas[packet_index] += 1;
+    }
+
+    /// Picks up to `count` packets the peer has (per `peer_availability`) that are neither
+    /// downloaded nor requested yet, and marks them requested
+    pub fn assign(&mut self, peer_availability: &BitVec, count: usize) -> Vec<usize> {
+        let candidates = (0..self.downloaded.len())
+            .filter(|&i| {
+                !self.downloaded[i]
+                    && self.requested[i] == 0
+                    && peer_availability.get(i).unwrap_or(false)
+            })
+            .collect();
+        let assigned = self.picker.pick(candidates, &self.replicas, count);
+
+        for &i in &assigned {
+            self.requested[i] += 1;
+        }
+        assigned
+    }
+
+    /// Whether every packet left is requested from some peer, so that a slow peer would hold
+    /// up the whole download
+    pub fn is_endgame(&self) -> bool {
+        !self.is_done()
+            && (0..self.downloaded.len()).all(|i| self.downloaded[i] || self.requested[i] > 0)
+    }
+
+    /// Endgame counterpart of `assign`, picking packets left regardless of whether they're
+    /// requested from other peers already (those requested from the fewest peers first)
+    ///
+    /// `is_pending(i)` tells whether packet `i` is already requested from this peer
+    pub fn assign_endgame(
+        &mut self,
+        peer_availability: &BitVec,
+        count: usize,
+        is_pending: impl Fn(usize) -> bool,
+    ) -> Vec<usize> {
+        let mut candidates: Vec<usize> = (0..self.downloaded.len())
+            .filter(|&i| {
+                !self.downloaded[i] && !is_pending(i) && peer_availability.get(i).unwrap_or(false)
+            })
+            .collect();
+        candidates.sort_by_key(|&i| self.requested[i]);
+        candidates.truncate(count);
+
+        for &i in &candidates {
+            self.requested[i] += 1;
+        }
+        candidates
+    }
+
+    pub fn is_downloaded(&self, packet_index: usize) -> bool {
+        self.downloaded[packet_index]
+    }
+
+    /// Whether the peer has any packet that isn't downloaded yet
+    pub fn is_interesting(&self, peer_availability: &BitVec) -> bool {
+        (0..self.downloaded.len())
+            .any(|i| !self.downloaded[i] && peer_availability.get(i).unwrap_or(false))
+    }
+
+    /// Returns a requested packet back to the pool, e.g. when its peer disconnected
+    ///
+    /// In the endgame, the packet stays requested while other peers have it pending
+    pub fn release(&mut self, packet_index: usize) {
+        self.requested[packet_index] = self.requested[packet_index].saturating_sub(1);
+    }
+
+    /// Returns false if the packet was downloaded already, e.g. from another peer in the endgame
+    pub fn complete(&mut self, packet_index: usize) -> bool {
+        self.requested[packet_index] = 0;
+        let newly_downloaded = !self.downloaded[packet_index];
+        self.downloaded.set(packet_index, true);
+        newly_downloaded
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.downloaded.all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+    use crate::piece_picker::RarestFirst;
+
+    #[test]
+    fn Scheduler_assigns_only_available_packets() {
+        let mut scheduler = Scheduler::new(BitVec::from_elem(8, false));
+        let peer = BitVec::from_bytes(&[0b0101_0000]);
+
+        assert_eq!(scheduler.assign(&peer, 5), vec![1, 3]);
+    }
+
+    #[test]
+    fn Scheduler_doesnt_assign_twice() {
+        let mut scheduler = Scheduler::new(BitVec::from_bytes(&[0b1000_0000]));
+        let peer = BitVec::from_elem(8, true);
+
+        assert_eq!(scheduler.assign(&peer, 2), vec![1, 2]);
+        assert_eq!(scheduler.assign(&peer, 2), vec![3, 4]);
+
+        scheduler.release(2);
+        scheduler.complete(1);
+        assert_eq!(scheduler.assign(&peer, 2), vec![2, 5]);
+    }
+
+    #[test]
+    fn Scheduler_is_interesting() {
+        let mut scheduler = Scheduler::new(BitVec::from_bytes(&[0b1100_0000]));
+        let peer = BitVec::from_bytes(&[0b1110_0000]);
+        assert!(!scheduler.is_interesting(&BitVec::from_bytes(&[0b1100_0000])));
+        assert!(scheduler.is_interesting(&peer));
+
+        // Requested from another peer, but not downloaded yet
+        scheduler.assign(&peer, 1);
+        assert!(scheduler.is_interesting(&peer));
+
+        scheduler.complete(2);
+        assert!(!scheduler.is_interesting(&peer));
+    }
+
+    #[test]
+    fn Scheduler_is_done() {
+        let mut scheduler = Scheduler::new(BitVec::from_bytes(&[0b1111_1110]));
+        assert!(!scheduler.is_done());
+
+        let assigned = scheduler.assign(&BitVec::from_elem(8, true), 8);
+        assert_eq!(assigned, vec![7]);
+        assert!(!scheduler.is_done());
+
+        scheduler.complete(7);
+        assert!(scheduler.is_done());
+    }
+
+    #[test]
+    fn Scheduler_assigns_rarest_packets() {
+        let mut scheduler =
+            Scheduler::new(BitVec::from_elem(8, false)).with_picker(Arc::new(RarestFirst));
+        let seed = BitVec::from_elem(8, true);
+        let leech = BitVec::from_bytes(&[0b1111_0000]);
+        scheduler.add_peer(&seed);
+        scheduler.add_peer(&leech);
+        scheduler.add_replica(4);
+
+        let mut assigned = scheduler.assign(&seed, 3);
+        assigned.sort();
+        assert_eq!(assigned, vec![5, 6, 7]);
+
+        // Once the leech leaves, its packets are as rare as the rest
+        scheduler.remove_peer(&leech);
+        assert_eq!(scheduler.replicas, vec![1, 1, 1, 1, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn Scheduler_endgame() {
+        let mut scheduler = Scheduler::new(BitVec::from_bytes(&[0b1111_1000]));
+        let peer = BitVec::from_elem(8, true);
+
+        assert_eq!(scheduler.assign(&peer, 2), vec![5, 6]);
+        assert!(!scheduler.is_endgame());
+        assert_eq!(scheduler.assign(&peer, 2), vec![7]);
+        assert!(scheduler.is_endgame());
+
+        // Another peer asks for all, its own pending packet aside, the least requested first
+        scheduler.assign_endgame(&peer, 1, |_| false);
+        assert_eq!(scheduler.assign_endgame(&peer, 3, |i| i == 7), vec![6, 5]);
+
+        // Packet 5 stays requested from the other peer
+        scheduler.release(5);
+        assert!(scheduler.is_endgame());
+        assert!(scheduler.assign(&peer, 1).is_empty());
+
+        scheduler.complete(5);
+        scheduler.release(5);
+        assert!(scheduler.is_downloaded(5));
+        scheduler.complete(6);
+        scheduler.complete(7);
+        assert!(!scheduler.is_endgame());
+        assert!(scheduler.is_done());
+    }
+}
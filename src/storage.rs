@@ -0,0 +1,247 @@
+use std::cmp::min;
+use std::fs::{self as std_fs, File as StdFile};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// A single file of a multi-file torrent
+pub struct FileEntry {
+    /// Relative to the torrent's root directory
+    pub path: PathBuf,
+    pub length: usize,
+}
+
+impl FileEntry {
+    /// Checks that `path` stays inside the torrent's directory (no `..`, no absolute paths)
+    pub fn is_path_safe(&self) -> bool {
+        self.path.components().count() > 0
+            && self
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+    }
+}
+
+/// Lists all files under `root`, recursively, sorted by path
+pub fn scan_directory(root: &str) -> io::Result<Vec<FileEntry>> {
+    fn scan(root: &Path, dir: &Path, entries: &mut Vec<FileEntry>) -> io::Result<()> {
+        for entry in std_fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = std_fs::metadata(&path)?;
+            if metadata.is_dir() {
+                scan(root, &path, entries)?;
+            } else {
+                entries.push(FileEntry {
+                    path: path.strip_prefix(root).unwrap().to_owned(),
+                    length: metadata.len() as usize,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    let mut entries = vec![];
+    scan(Path::new(root), Path::new(root), &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How `Storage::open` treats files on disk
+pub enum OpenMode {
+    /// Creates missing directories and files, truncating existing files
+    Create,
+    /// Opens existing files for reading only
+    ReadOnly,
+    /// Opens existing files for reading and writing, without truncating
+    ReadWrite,
+    /// Like `Create`, but keeps the contents of existing files
+    CreateOrOpen,
+}
+
+struct StorageFile {
+    /// Offset of the file's first byte in the torrent
+    offset: usize,
+    length: usize,
+    file: File,
+}
+
+/// Maps the torrent's contiguous byte range onto one or more files
+pub struct Storage {
+    files: Vec<StorageFile>,
+}
+
+impl Storage {
+    /// Opens `path` as a single file of `torrent_size` bytes or, when `files` are given,
+    /// as a directory containing them
+    pub fn open(
+        path: &str,
+        files: Option<&[FileEntry]>,
+        torrent_size: usize,
+        mode: OpenMode,
+    ) -> io::Result<Self> {
+        let entries = match files {
+            Some(files) => files
+                .iter()
+                .map(|entry| {
+                    if !entry.is_path_safe() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Unsafe file path {}.", entry.path.display()),
+                        ));
+                    }
+                    Ok((Path::new(path).join(&entry.path), entry.length))
+                })
+                .collect::<io::Result<Vec<_>>>()?,
+            None => vec![(PathBuf::from(path), torrent_size)],
+        };
+
+        let mut offset = 0;
+        let mut files = vec![];
+        for (path, length) in entries {
+            let create = matches!(mode, OpenMode::Create | OpenMode::CreateOrOpen);
+            if create {
+                if let Some(parent) = path.parent() {
+                    std_fs::create_dir_all(parent)?;
+                }
+            }
+
+            let file = StdFile::options()
+                .read(true)
+                .write(mode != OpenMode::ReadOnly)
+                .create(create)
+                .truncate(mode == OpenMode::Create)
+                .open(&path)?;
+
+            files.push(StorageFile {
+                offset,
+                length,
+                file: File::from_std(file),
+            });
+            offset += length;
+        }
+
+        Ok(Self { files })
+    }
+
+    /// Files overlapping `len` bytes starting at `offset`, with the overlapping range of each
+    /// (position within the file, position within the buffer, length)
+    fn spans(
+        &mut self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (&mut File, usize, usize, usize)> {
+        let end = offset + len;
+        self.files
+            .iter_mut()
+            .filter(move |file| {
+                file.length > 0 && file.offset < end && offset < file.offset + file.length
+            })
+            .map(move |file| {
+                let span_start = offset.max(file.offset);
+                let span_end = min(end, file.offset + file.length);
+                (
+                    &mut file.file,
+                    span_start - file.offset,
+                    span_start - offset,
+                    span_end - span_start,
+                )
+            })
+    }
+
+    /// Fills `buf` with bytes starting at `offset`
+    pub async fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        for (file, file_pos, buf_pos, len) in self.spans(offset, buf.len()) {
+            file.seek(io::SeekFrom::Start(file_pos as u64)).await?;
+            file.read_exact(&mut buf[buf_pos..buf_pos + len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Flushes written data of all files to disk
+    pub async fn sync(&mut self) -> io::Result<()> {
+        for file in &mut self.files {
+            file.file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting at `offset`
+    pub async fn write_at(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        for (file, file_pos, buf_pos, len) in self.spans(offset, data.len()) {
+            file.seek(io::SeekFrom::Start(file_pos as u64)).await?;
+            file.write_all(&data[buf_pos..buf_pos + len]).await?;
+            file.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    fn entries() -> Vec<FileEntry> {
+        vec![
+            FileEntry {
+                path: PathBuf::from("a"),
+                length: 3,
+            },
+            FileEntry {
+                path: PathBuf::from("empty"),
+                length: 0,
+            },
+            FileEntry {
+                path: PathBuf::from("sub/dir/b"),
+                length: 5,
+            },
+            FileEntry {
+                path: PathBuf::from("sub/c"),
+                length: 2,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn Storage_write_read_across_files() {
+        let root = ".testfiles/Storage_write_read_across_files";
+        let mut storage = Storage::open(root, Some(&entries()), 10, OpenMode::Create).unwrap();
+
+        storage.write_at(0, b"ABCDabcdXY").await.unwrap();
+        let mut buf = [0u8; 6];
+        storage.read_at(2, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"CDabcd");
+
+        assert_eq!(std_fs::read(format!("{root}/a")).unwrap(), b"ABC");
+        assert_eq!(std_fs::read(format!("{root}/empty")).unwrap(), b"");
+        assert_eq!(std_fs::read(format!("{root}/sub/dir/b")).unwrap(), b"Dabcd");
+        assert_eq!(std_fs::read(format!("{root}/sub/c")).unwrap(), b"XY");
+    }
+
+    #[test]
+    fn scan_directory_sorted() {
+        let root = ".testfiles/scan_directory_sorted";
+        Storage::open(root, Some(&entries()), 10, OpenMode::Create).unwrap();
+
+        let mut expected = entries();
+        for entry in expected.iter_mut() {
+            entry.length = 0;
+        }
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(scan_directory(root).unwrap(), expected);
+    }
+
+    #[test]
+    fn Storage_rejects_unsafe_paths() {
+        let entries = vec![FileEntry {
+            path: PathBuf::from("../escaped"),
+            length: 1,
+        }];
+        assert!(Storage::open(".testfiles/unsafe", Some(&entries), 1, OpenMode::Create).is_err());
+    }
+}
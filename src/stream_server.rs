@@ -0,0 +1,393 @@
+use std::cmp::min;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::select;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::http_tracker::{percent_decode, RequestHead};
+use crate::piece_picker::Streaming;
+use crate::torrent_file::TorrentFile;
+use crate::tracker::accept_with_slot;
+
+/// Connections that send no complete request for this long are closed
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests served at once, each one may wait long for its packets
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+#[derive(Debug, PartialEq)]
+/// Bytes asked for by a request's `Range` header
+enum RangeRequest {
+    /// No header, or one that isn't understood, which is ignored as RFC 9110 requires
+    Whole,
+    /// Bytes [start; end] of the file
+    Range(usize, usize),
+    /// The range lies beyond the end of the file
+    Unsatisfiable,
+}
+
+/// Serves files of a torrent over HTTP while it's being downloaded, for players and readers to
+/// start consuming them before the download finishes
+///
+/// Requests wait for the packets they need, which are moved to the front of the download queue
+/// when the client uses the `Streaming` picker
+pub struct StreamServer {
+    torrent_file: Arc<TorrentFile>,
+    picker: Option<Arc<Streaming>>,
+    read_timeout: Duration,
+    max_connections: usize,
+}
+
+impl StreamServer {
+    pub fn new(torrent_file: Arc<TorrentFile>) -> Self {
+        Self {
+            torrent_file,
+            picker: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+
+    /// Makes requests move `picker`'s position to the packets they wait for
+    pub fn with_picker(mut self, picker: Arc<Streaming>) -> Self {
+        self.picker = Some(picker);
+        self
+    }
+
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Connections above `max_connections` wait until one of the served ones closes
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub async fn listen<T>(
+        &self,
+        addr: &T,
+        shutdown_channel: oneshot::Receiver<()>,
+    ) -> io::Result<()>
+    where
+        T: ToSocketAddrs,
+    {
+        select! {
+            res = shutdown_channel => res.map_err(|err| io::Error::other(err.to_string())),
+            res = self.do_listen(addr) => res,
+        }
+    }
+
+    /// Actual `listen` body, answering up to `max_connections` requests concurrently
+    pub async fn do_listen<T>(&self, addr: &T) -> io::Result<()>
+    where
+        T: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let connection_slots = Arc::new(Semaphore::new(self.max_connections));
+        let mut connections = JoinSet::new();
+
+        loop {
+            let (stream, slot) = select! {
+                res = accept_with_slot(&listener, &connection_slots) => res?,
+                // Reaps finished connections
+                Some(_) = connections.join_next() => continue,
+            };
+
+            let torrent_file = Arc::clone(&self.torrent_file);
+            let picker = self.picker.clone();
+            let read_timeout = self.read_timeout;
+            connections.spawn(async move {
+                let _slot = slot;
+                handle_connection(stream, &torrent_file, picker.as_deref(), read_timeout).await
+            });
+        }
+    }
+}
+
+/// Answers a single GET or HEAD request for a file of the torrent, then closes the connection
+async fn handle_connection(
+    mut stream: TcpStream,
+    torrent_file: &TorrentFile,
+    picker: Option<&Streaming>,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+
+    let head = match RequestHead::read(&mut reader, read_timeout).await {
+        Ok(head) => head,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            writer.write_all(&error_response("400 Bad Request")).await?;
+            return writer.shutdown().await;
+        }
+        Err(_) => return Ok(()),
+    };
+    let head_only = match head.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            writer
+                .write_all(&error_response("405 Method Not Allowed"))
+                .await?;
+            return writer.shutdown().await;
+        }
+    };
+    let file_range = percent_decode(head.path().trim_start_matches('/'))
+        .ok()
+        .and_then(|path| String::from_utf8(path).ok())
+        .and_then(|path| torrent_file.file_range(Path::new(&path)));
+    let Some((offset, len)) = file_range else {
+        writer.write_all(&error_response("404 Not Found")).await?;
+        return writer.shutdown().await;
+    };
+
+    let (status, start, end) = match parse_range(head.header("range"), len) {
+        RangeRequest::Whole => ("200 OK", 0, len),
+        RangeRequest::Range(start, end) => ("206 Partial Content", start, end + 1),
+        RangeRequest::Unsatisfiable => {
+            let response = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{len}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            writer.write_all(response.as_bytes()).await?;
+            return writer.shutdown().await;
+        }
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nAccept-Ranges: bytes\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+        end - start
+    );
+    if status != "200 OK" {
+        response += &format!("Content-Range: bytes {start}-{}/{len}\r\n", end - 1);
+    }
+    response += "Connection: close\r\n\r\n";
+    writer.write_all(response.as_bytes()).await?;
+
+    if !head_only {
+        write_bytes(
+            &mut writer,
+            torrent_file,
+            picker,
+            offset + start,
+            offset + end,
+        )
+        .await?;
+    }
+    writer.shutdown().await
+}
+
+/// Writes bytes [start; end) of the torrent's data, waiting for each packet to be downloaded
+async fn write_bytes<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    torrent_file: &TorrentFile,
+    picker: Option<&Streaming>,
+    start: usize,
+    end: usize,
+) -> io::Result<()> {
+    if start >= end {
+        return Ok(());
+    }
+
+    let packet_size = torrent_file.packet_size();
+    for packet_index in start / packet_size..=(end - 1) / packet_size {
+        if let Some(picker) = picker {
+            picker.set_position(packet_index);
+        }
+        torrent_file.wait_for_packet(packet_index).await;
+
+        let packet = torrent_file.read_packets(packet_index, 1).await?;
+        let packet_start = packet_index * packet_size;
+        let from = start.saturating_sub(packet_start);
+        let to = min(end - packet_start, packet.len());
+        writer.write_all(&packet[from..to]).await?;
+    }
+    Ok(())
+}
+
+/// Interprets the `Range` header value of a request for a file of `len` bytes
+///
+/// Only single ranges are supported, requests for several are answered with the whole file
+fn parse_range(header: Option<&str>, len: usize) -> RangeRequest {
+    let Some(range) = header.and_then(|header| header.strip_prefix("bytes=")) else {
+        return RangeRequest::Whole;
+    };
+    let Some((first, last)) = range.trim().split_once('-') else {
+        return RangeRequest::Whole;
+    };
+    if range.contains(',') {
+        return RangeRequest::Whole;
+    }
+
+    match (first.parse::<usize>(), last.parse::<usize>()) {
+        // The last `last` bytes
+        (Err(_), Ok(last)) if first.is_empty() => match last {
+            0 => RangeRequest::Unsatisfiable,
+            _ if len == 0 => RangeRequest::Unsatisfiable,
+            _ => RangeRequest::Range(len.saturating_sub(last), len - 1),
+        },
+        (Ok(first), Err(_)) if last.is_empty() => match first < len {
+            true => RangeRequest::Range(first, len - 1),
+            false => RangeRequest::Unsatisfiable,
+        },
+        (Ok(first), Ok(last)) if first <= last => match first < len {
+            true => RangeRequest::Range(first, min(last, len - 1)),
+            false => RangeRequest::Unsatisfiable,
+        },
+        _ => RangeRequest::Whole,
+    }
+}
+
+/// Response without a body other than its status, after which the connection is closed
+fn error_response(status: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{status}",
+        status.len()
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use tokio::io::AsyncReadExt;
+    use tokio::time;
+
+    use super::*;
+    use crate::storage::FileEntry;
+
+    #[test]
+    fn parse_range_cases() {
+        use RangeRequest::*;
+
+        assert_eq!(parse_range(None, 10), Whole);
+        assert_eq!(parse_range(Some("bytes=2-5"), 10), Range(2, 5));
+        assert_eq!(parse_range(Some("bytes=2-"), 10), Range(2, 9));
+        assert_eq!(parse_range(Some("bytes=-3"), 10), Range(7, 9));
+        assert_eq!(parse_range(Some("bytes=-30"), 10), Range(0, 9));
+        assert_eq!(parse_range(Some("bytes=8-20"), 10), Range(8, 9));
+        assert_eq!(parse_range(Some("bytes=10-"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=5-2"), 10), Whole);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), Whole);
+        assert_eq!(parse_range(Some("items=0-1"), 10), Whole);
+    }
+
+    async fn get(addr: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn StreamServer_waits_for_packets() {
+        let addr = "127.0.0.1:46125";
+        let filename = ".testfiles/StreamServer_waits_for_packets";
+        let torrent_file = Arc::new(TorrentFile::new(filename, 10, 4).unwrap());
+        torrent_file.write_packets(0, b"0123").await.unwrap();
+        let picker = Arc::new(Streaming::default());
+        let server = StreamServer::new(Arc::clone(&torrent_file)).with_picker(Arc::clone(&picker));
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let server = tokio::spawn(async move { server.listen(&addr, shutdown_receiver).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let response = get(addr, "GET /movie HTTP/1.1\r\nRange: bytes=2-5\r\n\r\n");
+        let response = tokio::spawn(response);
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!response.is_finished());
+        assert_eq!(picker.position(), 1);
+
+        torrent_file.write_packets(1, b"4567").await.unwrap();
+        let response = time::timeout(Duration::from_millis(500), response)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 2-5/10\r\n"));
+        assert!(response.ends_with("\r\n\r\n2345"));
+
+        let response = get(addr, "HEAD / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 10\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = get(addr, "GET / HTTP/1.1\r\nrange: bytes=10-\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("Content-Range: bytes */10\r\n"));
+
+        shutdown_sender.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn StreamServer_serves_files_of_multi_file_torrents() {
+        let addr = "127.0.0.1:46126";
+        let files = vec![
+            FileEntry {
+                path: "a".into(),
+                length: 3,
+            },
+            FileEntry {
+                path: "sub dir/b".into(),
+                length: 5,
+            },
+        ];
+        let root = ".testfiles/StreamServer_serves_files_of_multi_file_torrents";
+        let torrent_file = Arc::new(TorrentFile::new_multi_file(root, files, 4).unwrap());
+        torrent_file.write_packets(0, b"abcBBBBB").await.unwrap();
+        let server = StreamServer::new(torrent_file);
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let server = tokio::spawn(async move { server.listen(&addr, shutdown_receiver).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let response = get(addr, "GET /sub%20dir/b HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nBBBBB"));
+
+        let response = get(addr, "GET /c HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown_sender.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn StreamServer_limits_connections() {
+        let addr = "127.0.0.1:46140";
+        let filename = ".testfiles/StreamServer_limits_connections";
+        let torrent_file = Arc::new(TorrentFile::new(filename, 8, 4).unwrap());
+        let server = StreamServer::new(Arc::clone(&torrent_file)).with_max_connections(1);
+        let (_shutdown_sender, shutdown_receiver) = oneshot::channel();
+        tokio::spawn(async move { server.listen(&addr, shutdown_receiver).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // Waits for a packet, holding the only connection slot
+        let waiting = tokio::spawn(get(addr, "GET / HTTP/1.1\r\n\r\n"));
+        time::sleep(Duration::from_millis(50)).await;
+        let head = tokio::spawn(get(addr, "HEAD / HTTP/1.1\r\n\r\n"));
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!head.is_finished());
+
+        torrent_file.write_packets(0, b"01234567").await.unwrap();
+        let response = time::timeout(Duration::from_millis(500), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.ends_with("\r\n\r\n01234567"));
+        let response = time::timeout(Duration::from_millis(500), head)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
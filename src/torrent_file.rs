@@ -4,20 +4,33 @@ use std::str;
 
 use bit_vec::BitVec;
 use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
-use tokio::fs::{read, File, OpenOptions};
-use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::fs::{self, read, File};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::sync::{Mutex, Notify, RwLock};
 
-use std::fs::File as StdFile;
+use std::path::Path;
+
+use crate::metainfo::Metainfo;
+use crate::packet_hash::PacketHashes;
+use crate::storage::{scan_directory, FileEntry, OpenMode, Storage};
 
 /// Handles the logic of dividing the file into packets, writing and reading them.
 pub struct TorrentFile {
+    /// The file itself, or the root directory of a multi-file torrent
     path: String,
+    /// Files of a multi-file torrent, in the order their data appears in packets
+    files: Option<Vec<FileEntry>>,
     torrent_size: usize,
     packet_size: usize,
     packet_count: usize,
     packet_availability: RwLock<BitVec>,
-    file: RwLock<File>,
+    /// Expected hashes of packets; when present, packets are verified before being marked available
+    packet_hashes: Option<PacketHashes>,
+    storage: RwLock<Storage>,
+    /// Held while saving progress, so that concurrent saves don't share the temporary file
+    save_lock: Mutex<()>,
+    /// Wakes tasks waiting for packets whenever packets become available
+    packets_available: Notify,
 }
 
 /// Returns ceil(a/b)
@@ -34,45 +47,206 @@ fn div_usize_ceil(a: usize, b: usize) -> usize {
 
 impl TorrentFile {
     pub fn new(path: &str, torrent_size: usize, packet_size: usize) -> io::Result<Self> {
-        // std::fs::File
-        let file: StdFile = StdFile::options()
-            .write(true)
-            .read(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
+        let storage = Storage::open(path, None, torrent_size, OpenMode::Create)?;
+        Ok(Self::with_storage(
+            path,
+            None,
+            torrent_size,
+            packet_size,
+            false,
+            storage,
+        ))
+    }
 
-        // tokio::fs::File
-        let file = RwLock::new(File::from_std(file));
+    /// Creates the directory `path` containing empty `files`, for downloading a multi-file torrent
+    pub fn new_multi_file(
+        path: &str,
+        files: Vec<FileEntry>,
+        packet_size: usize,
+    ) -> io::Result<Self> {
+        let torrent_size = files.iter().map(|entry| entry.length).sum();
+        let storage = Storage::open(path, Some(&files), torrent_size, OpenMode::Create)?;
+        Ok(Self::with_storage(
+            path,
+            Some(files),
+            torrent_size,
+            packet_size,
+            false,
+            storage,
+        ))
+    }
 
-        // currently stable Rust offers no straightforward integer ceil division
+    fn with_storage(
+        path: &str,
+        files: Option<Vec<FileEntry>>,
+        torrent_size: usize,
+        packet_size: usize,
+        available: bool,
+        storage: Storage,
+    ) -> Self {
         let packet_count = div_usize_ceil(torrent_size, packet_size);
-
-        let path = path.to_owned();
         let mut packet_availability = BitVec::new();
-        packet_availability.grow(packet_count, false);
-        let packet_availability = RwLock::new(packet_availability);
+        packet_availability.grow(packet_count, available);
 
-        Ok(Self {
-            path,
+        Self {
+            path: path.to_owned(),
+            files,
             torrent_size,
             packet_size,
             packet_count,
-            packet_availability,
-            file,
+            packet_availability: RwLock::new(packet_availability),
+            packet_hashes: None,
+            storage: RwLock::new(storage),
+            save_lock: Mutex::new(()),
+            packets_available: Notify::new(),
+        }
+    }
+
+    /// Creates an empty file (or directory) at `path` for downloading the torrent described by `metainfo`
+    ///
+    /// Packets are verified against the metainfo's piece hashes
+    pub fn from_metainfo(path: &str, metainfo: &Metainfo) -> io::Result<Self> {
+        let info = metainfo.info();
+        let torrent_file = match info.files() {
+            Some(files) => Self::new_multi_file(path, files.to_vec(), info.piece_length())?,
+            None => Self::new(path, info.length(), info.piece_length())?,
+        };
+        torrent_file.with_packet_hashes(info.pieces().clone())
+    }
+
+    /// Reopens a partial download of `metainfo` at `path` if it has a progress file,
+    /// without truncating it, or creates an empty one like `from_metainfo` otherwise
+    pub async fn resume(path: &str, metainfo: &Metainfo) -> io::Result<Self> {
+        let progress_path = format!("{path}.progress");
+        if !Path::new(&progress_path).exists() {
+            return Self::from_metainfo(path, metainfo);
+        }
+
+        let torrent_file = Self::from_progress_file(&progress_path).await?;
+        let info = metainfo.info();
+        let matches = torrent_file.path == path
+            && torrent_file.torrent_size == info.length()
+            && torrent_file.packet_size == info.piece_length()
+            && torrent_file.files.as_deref() == info.files()
+            && torrent_file
+                .packet_hashes
+                .as_ref()
+                .is_none_or(|hashes| hashes == info.pieces());
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{progress_path} belongs to a different torrent."),
+            ));
+        }
+
+        // Progress files saved before packet hashes were introduced lack them
+        torrent_file.with_packet_hashes(info.pieces().clone())
+    }
+
+    /// Opens the torrent described by `metainfo` at `path` keeping any data already there,
+    /// with no packet available until `recheck` finds them
+    ///
+    /// Missing files are created empty
+    pub fn from_existing(path: &str, metainfo: &Metainfo) -> io::Result<Self> {
+        let info = metainfo.info();
+        let storage = Storage::open(path, info.files(), info.length(), OpenMode::CreateOrOpen)?;
+        Self::with_storage(
+            path,
+            info.files().map(<[FileEntry]>::to_vec),
+            info.length(),
+            info.piece_length(),
+            false,
+            storage,
+        )
+        .with_packet_hashes(info.pieces().clone())
+    }
+
+    /// Verifies every available packet against the packet hashes, marking those that fail
+    /// (or can't be read) unavailable again
+    ///
+    /// Returns the number of packets which failed
+    pub async fn recheck_available_packets(&self) -> io::Result<usize> {
+        let packet_hashes = self.packet_hashes_to_recheck()?;
+        let available = self.read_packet_availability().await;
+        let mut failed = 0;
+        for i in (0..self.packet_count).filter(|&i| available[i]) {
+            if !self.verify_packet(packet_hashes, i).await? {
+                self.packet_availability.write().await.set(i, false);
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Verifies all packets on disk against the packet hashes, whether they're available or not,
+    /// and rebuilds packet availability from the result
+    ///
+    /// `on_progress` is called with the number of packets checked so far after each packet.
+    /// Returns the number of available packets
+    pub async fn recheck(&self, mut on_progress: impl FnMut(usize)) -> io::Result<usize> {
+        let packet_hashes = self.packet_hashes_to_recheck()?;
+        let mut available = 0;
+        for i in 0..self.packet_count {
+            let valid = self.verify_packet(packet_hashes, i).await?;
+            self.packet_availability.write().await.set(i, valid);
+            available += valid as usize;
+            on_progress(i + 1);
+        }
+        self.packets_available.notify_waiters();
+        Ok(available)
+    }
+
+    fn packet_hashes_to_recheck(&self) -> io::Result<&PacketHashes> {
+        self.packet_hashes.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No packet hashes to recheck against.",
+            )
         })
     }
 
+    /// Checks whether data of packet `packet_index` on disk matches its hash
+    async fn verify_packet(
+        &self,
+        packet_hashes: &PacketHashes,
+        packet_index: usize,
+    ) -> io::Result<bool> {
+        let mut packet = vec![0u8; self.packet_len(packet_index)];
+        let res = self
+            .storage
+            .write()
+            .await
+            .read_at(packet_index * self.packet_size, &mut packet)
+            .await;
+        match res {
+            Ok(()) => Ok(packet_hashes.verify(packet_index, &packet)),
+            // Data files shorter than they should be
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
     /// Saves torrent metadata and download progress to a file named `[torrent_name].progress`
+    ///
+    /// Data is synced to disk before the progress file claims it's there, and the previous
+    /// progress file is only replaced once the new one is fully written
     pub async fn save_progress_to_file(&self) -> io::Result<()> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(format!("{}.progress", self.path))
-            .await?;
-
-        file.write_all(&serde_json::to_vec(&self)?).await
+        let _lock = self.save_lock.lock().await;
+        // Packets are written before being marked available, so syncing after taking
+        // the snapshot covers every packet in it
+        let packet_availability = self.read_packet_availability().await;
+        self.storage.write().await.sync().await?;
+        let progress = serde_json::to_vec(&Progress {
+            torrent_file: self,
+            packet_availability: &packet_availability,
+        })?;
+
+        let path = format!("{}.progress", self.path);
+        let temp_path = format!("{path}.tmp");
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(&progress).await?;
+        file.sync_all().await?;
+        fs::rename(temp_path, path).await
     }
 
     /// Creates the struct based on metadata saved to a progress file
@@ -84,49 +258,119 @@ impl TorrentFile {
         Ok(deserialized)
     }
 
-    /// Creates the struct assuming the file pointed to by `path` is correct and downloaded wholly
+    /// Creates the struct assuming the file (or directory) pointed to by `path` is correct and downloaded wholly
     ///
-    /// `from_progress_file` should be preferred over this one
+    /// `from_progress_file` should be preferred over this one, or `from_existing` followed by
+    /// `recheck` when the data can't be trusted
     pub fn from_complete(path: &str, packet_size: usize) -> io::Result<Self> {
-        let file = StdFile::options().read(true).truncate(false).open(path)?;
-        let torrent_size = file.metadata()?.len() as usize;
-
-        let packet_count = div_usize_ceil(torrent_size, packet_size);
-        let mut packet_availability = BitVec::new();
-        packet_availability.grow(packet_count, true);
-        let packet_availability = RwLock::new(packet_availability);
+        let (files, torrent_size) = if Path::new(path).is_dir() {
+            let files = scan_directory(path)?;
+            let torrent_size = files.iter().map(|entry| entry.length).sum();
+            (Some(files), torrent_size)
+        } else {
+            (None, std::fs::metadata(path)?.len() as usize)
+        };
 
-        let file = RwLock::new(File::from_std(file));
-        Ok(Self {
-            path: path.to_owned(),
+        let storage = Storage::open(path, files.as_deref(), torrent_size, OpenMode::ReadOnly)?;
+        Ok(Self::with_storage(
+            path,
+            files,
             torrent_size,
             packet_size,
-            packet_count,
-            packet_availability,
-            file,
-        })
+            true,
+            storage,
+        ))
+    }
+
+    /// Makes `write_packets` verify incoming packets against `packet_hashes`
+    pub fn with_packet_hashes(mut self, packet_hashes: PacketHashes) -> io::Result<Self> {
+        if packet_hashes.len() != self.packet_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected {} packet hashes, got {}.",
+                    self.packet_count,
+                    packet_hashes.len()
+                ),
+            ));
+        }
+
+        self.packet_hashes = Some(packet_hashes);
+        Ok(self)
+    }
+
+    pub fn packet_hashes(&self) -> Option<&PacketHashes> {
+        self.packet_hashes.as_ref()
     }
 
     pub fn packet_count(&self) -> usize {
         self.packet_count
     }
 
+    pub fn torrent_size(&self) -> usize {
+        self.torrent_size
+    }
+
+    /// Byte range (offset, length) of the file at `path` within the torrent's data
+    ///
+    /// Single-file torrents consist of just one file, whatever `path` is
+    pub fn file_range(&self, path: &Path) -> Option<(usize, usize)> {
+        let Some(files) = &self.files else {
+            return Some((0, self.torrent_size));
+        };
+
+        let mut offset = 0;
+        for entry in files {
+            if entry.path == path {
+                return Some((offset, entry.length));
+            }
+            offset += entry.length;
+        }
+        None
+    }
+
+    /// Waits until packet `packet_index` is available, e.g. downloaded by another task
+    pub async fn wait_for_packet(&self, packet_index: usize) {
+        loop {
+            let packets_available = self.packets_available.notified();
+            if self.packet_availability.read().await[packet_index] {
+                return;
+            }
+            packets_available.await;
+        }
+    }
+
     /// Acquires internal RwLock and returns BitVec representing which packets are available and which are not
     pub async fn read_packet_availability(&self) -> BitVec {
         self.packet_availability.read().await.clone()
     }
 
+    /// Bytes of packets that aren't available yet
+    pub async fn bytes_left(&self) -> usize {
+        let availability = self.packet_availability.read().await;
+        (0..self.packet_count)
+            .filter(|&i| !availability[i])
+            .map(|i| self.packet_len(i))
+            .sum()
+    }
+
     pub fn packet_size(&self) -> usize {
         self.packet_size
     }
 
+    /// Length of packet `packet_index` in bytes, only the last packet may be shorter than `packet_size`
+    pub fn packet_len(&self, packet_index: usize) -> usize {
+        min(
+            self.packet_size,
+            self.torrent_size
+                .saturating_sub(packet_index * self.packet_size),
+        )
+    }
+
     /// Reads packets [start; start + count] from a file
     pub async fn read_packets(&self, start: usize, count: usize) -> io::Result<Vec<u8>> {
         if start + count > self.packet_count {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Packet out of bounds".to_owned(),
-            ));
+            return Err(io::Error::other("Packet out of bounds".to_owned()));
         }
         let all_available = {
             let packet_availability = self.packet_availability.read().await;
@@ -135,12 +379,11 @@ impl TorrentFile {
                 .iter()
                 .skip(start)
                 .take(count)
-                .all(|bit| bit == true)
+                .all(|bit| bit)
         };
 
         if !all_available {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+            return Err(io::Error::other(
                 "Not all requested packets are available.".to_owned(),
             ));
         }
@@ -152,30 +395,85 @@ impl TorrentFile {
         );
         let mut buf = vec![0u8; bytes_to_read];
 
-        // For now, has to be a mutable `RwLockWriteGuard` as it actually modifies internal cursor
-        let mut reader = self.file.write().await;
-        reader
-            .seek(io::SeekFrom::Start((start * self.packet_size) as u64))
-            .await?;
-        reader.read_exact(&mut buf).await?;
+        // For now, has to be a mutable `RwLockWriteGuard` as it actually modifies internal cursors
+        let mut reader = self.storage.write().await;
+        reader.read_at(start * self.packet_size, &mut buf).await?;
 
         Ok(buf.to_owned())
     }
 
-    pub async fn write_packets(&self, start: usize, data: &[u8]) -> io::Result<()> {
-        let mut writer = self.file.write().await;
-        writer
-            .seek(io::SeekFrom::Start((start * self.packet_size) as u64))
+    /// Reads `length` bytes of packet `packet_index`, starting `begin` bytes into the packet
+    ///
+    /// Unlike `read_packets`, only the requested bytes are read from disk
+    pub async fn read_block(
+        &self,
+        packet_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        if packet_index >= self.packet_count || begin + length > self.packet_len(packet_index) {
+            return Err(io::Error::other("Block out of bounds".to_owned()));
+        }
+        if !self.packet_availability.read().await[packet_index] {
+            return Err(io::Error::other(
+                "Requested packet is not available.".to_owned(),
+            ));
+        }
+
+        let mut buf = vec![0u8; length];
+        let mut reader = self.storage.write().await;
+        reader
+            .read_at(packet_index * self.packet_size + begin, &mut buf)
             .await?;
-        writer.write_all(data).await?;
-        writer.flush().await?;
+        Ok(buf)
+    }
+
+    /// Writes packets starting at packet `start`
+    ///
+    /// If packet hashes are known, every packet is verified first and nothing is written on mismatch
+    /// (the error has `io::ErrorKind::InvalidData` kind)
+    ///
+    /// Packets which are available already are skipped, e.g. copies received from several
+    /// peers in the endgame
+    pub async fn write_packets(&self, start: usize, data: &[u8]) -> io::Result<()> {
+        let end = start
+            .checked_mul(self.packet_size)
+            .and_then(|offset| offset.checked_add(data.len()));
+        if end.is_none_or(|end| end > self.torrent_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Packets out of bounds.",
+            ));
+        }
+
+        if let Some(packet_hashes) = &self.packet_hashes {
+            for (offset, packet) in data.chunks(self.packet_size).enumerate() {
+                if !packet_hashes.verify(start + offset, packet) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Packet {} failed hash verification.", start + offset),
+                    ));
+                }
+            }
+        }
+
+        // Held throughout, so that concurrent writes of a packet see each other's availability
+        let mut writer = self.storage.write().await;
+        let available = self.read_packet_availability().await;
+        for (offset, packet) in data.chunks(self.packet_size).enumerate() {
+            if !available.get(start + offset).unwrap_or(false) {
+                writer
+                    .write_at((start + offset) * self.packet_size, packet)
+                    .await?;
+            }
+        }
 
         let mut availability_lock = self.packet_availability.write().await;
         for i in start..(start + div_usize_ceil(data.len(), self.packet_size)) {
             availability_lock.set(i, true);
         }
+        self.packets_available.notify_waiters();
 
-        writer.flush().await?;
         Ok(())
     }
 }
@@ -185,15 +483,34 @@ impl Serialize for TorrentFile {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("FileHandler", 5)?;
-        state.serialize_field("path", &self.path)?;
-        state.serialize_field("torrent_size", &self.torrent_size)?;
-        state.serialize_field("packet_size", &self.packet_size)?;
-        state.serialize_field("packet_count", &self.packet_count)?;
-        state.serialize_field(
-            "packet_availability",
-            &self.packet_availability.try_read().unwrap().deref(),
-        )?;
+        Progress {
+            torrent_file: self,
+            packet_availability: self.packet_availability.try_read().unwrap().deref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Serialized form of `TorrentFile`, with a snapshot of its packet availability
+struct Progress<'a> {
+    torrent_file: &'a TorrentFile,
+    packet_availability: &'a BitVec,
+}
+
+impl Serialize for Progress<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let torrent_file = self.torrent_file;
+        let mut state = serializer.serialize_struct("FileHandler", 7)?;
+        state.serialize_field("path", &torrent_file.path)?;
+        state.serialize_field("torrent_size", &torrent_file.torrent_size)?;
+        state.serialize_field("packet_size", &torrent_file.packet_size)?;
+        state.serialize_field("packet_count", &torrent_file.packet_count)?;
+        state.serialize_field("packet_availability", self.packet_availability)?;
+        state.serialize_field("packet_hashes", &torrent_file.packet_hashes)?;
+        state.serialize_field("files", &torrent_file.files)?;
         state.end()
     }
 }
@@ -208,6 +525,8 @@ impl<'de> Deserialize<'de> for TorrentFile {
             "packet_size",
             "packet_count",
             "packet_availability",
+            "packet_hashes",
+            "files",
         ];
         deserializer.deserialize_struct("FileHandler", FIELDS, FileHandlerVisitor)
     }
@@ -215,6 +534,39 @@ impl<'de> Deserialize<'de> for TorrentFile {
 
 struct FileHandlerVisitor;
 
+/// Checks that the packet layout read from a progress file is consistent, as the rest of
+/// `TorrentFile` indexes packets without checking bounds
+fn check_packet_layout<E: serde::de::Error>(
+    torrent_size: usize,
+    packet_size: usize,
+    packet_count: usize,
+    packet_availability: &BitVec,
+    packet_hashes: Option<&PacketHashes>,
+) -> Result<(), E> {
+    if packet_size == 0 {
+        return Err(E::custom("Packet size is zero."));
+    }
+    let expected = div_usize_ceil(torrent_size, packet_size);
+    if packet_count != expected {
+        return Err(E::custom(format!(
+            "Expected {expected} packets, got {packet_count}."
+        )));
+    }
+    if packet_availability.len() != packet_count {
+        return Err(E::custom(format!(
+            "Expected availability of {packet_count} packets, got {}.",
+            packet_availability.len()
+        )));
+    }
+    if let Some(packet_hashes) = packet_hashes.filter(|hashes| hashes.len() != packet_count) {
+        return Err(E::custom(format!(
+            "Expected {packet_count} packet hashes, got {}.",
+            packet_hashes.len()
+        )));
+    }
+    Ok(())
+}
+
 impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
     type Value = TorrentFile;
 
@@ -226,7 +578,7 @@ impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
     where
         A: serde::de::SeqAccess<'de>,
     {
-        let path = seq
+        let path: String = seq
             .next_element()?
             .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
         let torrent_size = seq
@@ -238,28 +590,34 @@ impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
         let packet_count = seq
             .next_element()?
             .ok_or_else(|| serde::de::Error::invalid_length(3, &self))?;
-        let packet_availability = RwLock::new(
-            seq.next_element()?
-                .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?,
-        );
-
-        let file = StdFile::options()
-            .write(true)
-            .read(true)
-            .create(false)
-            .truncate(false)
-            .open(&path)
-            .unwrap();
+        let packet_availability: BitVec = seq
+            .next_element()?
+            .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?;
+        // Progress files saved before packet hashes or multi-file torrents were introduced lack these fields
+        let packet_hashes: Option<PacketHashes> = seq.next_element()?.flatten();
+        let files: Option<Vec<FileEntry>> = seq.next_element()?.flatten();
+        check_packet_layout(
+            torrent_size,
+            packet_size,
+            packet_count,
+            &packet_availability,
+            packet_hashes.as_ref(),
+        )?;
 
-        let file = RwLock::new(File::from_std(file));
+        let storage = Storage::open(&path, files.as_deref(), torrent_size, OpenMode::ReadWrite)
+            .map_err(serde::de::Error::custom)?;
 
         Ok(TorrentFile {
-            file,
+            storage: RwLock::new(storage),
             path,
+            files,
             torrent_size,
             packet_size,
             packet_count,
-            packet_availability,
+            packet_availability: RwLock::new(packet_availability),
+            packet_hashes,
+            save_lock: Mutex::new(()),
+            packets_available: Notify::new(),
         })
     }
 
@@ -272,6 +630,8 @@ impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
         let mut packet_size = None;
         let mut packet_count = None;
         let mut packet_availability = None;
+        let mut packet_hashes = None;
+        let mut files = None;
 
         while let Some(key) = map.next_key()? {
             match key {
@@ -303,7 +663,19 @@ impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
                     if packet_availability.is_some() {
                         return Err(serde::de::Error::duplicate_field("packet_availability"));
                     }
-                    packet_availability = Some(RwLock::new(map.next_value()?));
+                    packet_availability = Some(map.next_value()?);
+                }
+                "packet_hashes" => {
+                    if packet_hashes.is_some() {
+                        return Err(serde::de::Error::duplicate_field("packet_hashes"));
+                    }
+                    packet_hashes = Some(map.next_value()?);
+                }
+                "files" => {
+                    if files.is_some() {
+                        return Err(serde::de::Error::duplicate_field("files"));
+                    }
+                    files = Some(map.next_value()?);
                 }
                 _ => {
                     let _ = map.next_value::<serde::de::IgnoredAny>()?;
@@ -311,33 +683,40 @@ impl<'de> serde::de::Visitor<'de> for FileHandlerVisitor {
             }
         }
 
-        let path = path.ok_or_else(|| serde::de::Error::missing_field("path"))?;
+        let path: String = path.ok_or_else(|| serde::de::Error::missing_field("path"))?;
         let torrent_size =
             torrent_size.ok_or_else(|| serde::de::Error::missing_field("torrent_size"))?;
         let packet_size =
             packet_size.ok_or_else(|| serde::de::Error::missing_field("packet_size"))?;
         let packet_count =
             packet_count.ok_or_else(|| serde::de::Error::missing_field("packet_count"))?;
-        let packet_availability = packet_availability
+        let packet_availability: BitVec = packet_availability
             .ok_or_else(|| serde::de::Error::missing_field("packet_availability"))?;
+        // Progress files saved before packet hashes or multi-file torrents were introduced lack these fields
+        let packet_hashes: Option<PacketHashes> = packet_hashes.flatten();
+        let files: Option<Vec<FileEntry>> = files.flatten();
+        check_packet_layout(
+            torrent_size,
+            packet_size,
+            packet_count,
+            &packet_availability,
+            packet_hashes.as_ref(),
+        )?;
 
-        let file = StdFile::options()
-            .write(true)
-            .read(true)
-            .create(false)
-            .truncate(false)
-            .open(&path)
-            .unwrap();
-
-        let file = RwLock::new(File::from_std(file));
+        let storage = Storage::open(&path, files.as_deref(), torrent_size, OpenMode::ReadWrite)
+            .map_err(serde::de::Error::custom)?;
 
         Ok(TorrentFile {
-            file,
+            storage: RwLock::new(storage),
             path,
+            files,
             torrent_size,
             packet_size,
             packet_count,
-            packet_availability,
+            packet_availability: RwLock::new(packet_availability),
+            packet_hashes,
+            save_lock: Mutex::new(()),
+            packets_available: Notify::new(),
         })
     }
 }
@@ -347,7 +726,10 @@ mod tests {
     #![allow(non_snake_case)] // to allow structs' original case in test names
 
     use super::*;
+    use crate::packet_hash::HashAlgorithm;
+    use std::fs::File as StdFile;
     use std::io::Read;
+    use std::sync::Arc;
 
     #[test]
     fn div_usize_ceil_same_as_floor() {
@@ -460,6 +842,21 @@ mod tests {
         file.read_exact(&mut buf).unwrap();
     }
 
+    #[tokio::test]
+    async fn FileHandler_write_packets_out_of_bounds() {
+        let filename = ".testfiles/FileHandler_write_packets_out_of_bounds";
+        let handler = TorrentFile::new(filename, 10, 4).unwrap();
+
+        for (start, data) in [(3, &b"AB"[..]), (2, b"abc"), (0, b"ABCDabcdXYZ")] {
+            let err = handler.write_packets(start, data).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(handler.bytes_left().await, 10);
+
+        handler.write_packets(2, b"XY").await.unwrap();
+        assert_eq!(handler.read_packets(2, 1).await.unwrap(), b"XY");
+    }
+
     #[tokio::test]
     async fn FileHandler_read_packets_unavailable() {
         let filename = ".testfiles/FileHandler_write_packets_nondiv";
@@ -478,6 +875,19 @@ mod tests {
         file.read_exact(&mut buf).unwrap();
     }
 
+    #[tokio::test]
+    async fn FileHandler_read_block() {
+        let filename = ".testfiles/FileHandler_read_block";
+        let handler = TorrentFile::new(filename, 10, 4).unwrap();
+        handler.write_packets(1, "abcd".as_bytes()).await.unwrap();
+
+        assert_eq!(handler.read_block(1, 1, 2).await.unwrap(), b"bc");
+        assert_eq!(handler.read_block(1, 0, 4).await.unwrap(), b"abcd");
+        assert!(handler.read_block(1, 2, 3).await.is_err());
+        assert!(handler.read_block(0, 0, 1).await.is_err());
+        assert!(handler.read_block(3, 0, 1).await.is_err());
+    }
+
     #[tokio::test]
     async fn FileHandler_get_packet_availability() {
         let filename = ".testfiles/FileHandler_get_packet_availability";
@@ -505,4 +915,283 @@ mod tests {
 
         assert_eq!(deserialized.read_packets(0, 8).await.unwrap(), content)
     }
+
+    #[tokio::test]
+    async fn FileHandler_multi_file_packets_straddle_files() {
+        let root = ".testfiles/FileHandler_multi_file";
+        let files = vec![
+            FileEntry {
+                path: "a".into(),
+                length: 3,
+            },
+            FileEntry {
+                path: "sub/b".into(),
+                length: 7,
+            },
+        ];
+        let handler = TorrentFile::new_multi_file(root, files, 4).unwrap();
+        assert_eq!(handler.packet_count(), 3);
+
+        handler
+            .write_packets(0, "ABCDabcdXY".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.read_packets(0, 3).await.unwrap(),
+            "ABCDabcdXY".as_bytes()
+        );
+        assert_eq!(
+            std::fs::read(format!("{root}/a")).unwrap(),
+            "ABC".as_bytes()
+        );
+        assert_eq!(
+            std::fs::read(format!("{root}/sub/b")).unwrap(),
+            "DabcdXY".as_bytes()
+        );
+
+        let complete = TorrentFile::from_complete(root, 4).unwrap();
+        assert_eq!(
+            complete.read_packets(1, 2).await.unwrap(),
+            "abcdXY".as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn FileHandler_write_packets_verified() {
+        let content = "ABCDabcdXY".as_bytes();
+        let filename = ".testfiles/FileHandler_write_packets_verified";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha256, content, 4);
+        let handler = TorrentFile::new(filename, 10, 4)
+            .unwrap()
+            .with_packet_hashes(hashes)
+            .unwrap();
+
+        handler.write_packets(0, &content[..8]).await.unwrap();
+        handler.write_packets(2, &content[8..]).await.unwrap();
+        assert_eq!(handler.read_packets(0, 3).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn FileHandler_write_packets_corrupt() {
+        let filename = ".testfiles/FileHandler_write_packets_corrupt";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, "ABCDabcd".as_bytes(), 4);
+        let handler = TorrentFile::new(filename, 8, 4)
+            .unwrap()
+            .with_packet_hashes(hashes)
+            .unwrap();
+
+        let err = handler
+            .write_packets(0, "ABCDabce".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(handler.read_packet_availability().await.none());
+    }
+
+    #[tokio::test]
+    async fn FileHandler_with_packet_hashes_wrong_count() {
+        let filename = ".testfiles/FileHandler_with_packet_hashes_wrong_count";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, "ABCD".as_bytes(), 4);
+        let handler = TorrentFile::new(filename, 8, 4).unwrap();
+
+        assert!(handler.with_packet_hashes(hashes).is_err());
+    }
+
+    #[tokio::test]
+    async fn FileHandler_serde_packet_hashes() {
+        let content = "ABCDabcd".as_bytes();
+        let filename = ".testfiles/FileHandler_serde_packet_hashes";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, content, 4);
+        let handler = TorrentFile::new(filename, 8, 4)
+            .unwrap()
+            .with_packet_hashes(hashes.clone())
+            .unwrap();
+
+        let serialized = serde_json::to_string(&handler).unwrap();
+        let deserialized: TorrentFile = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.packet_hashes(), Some(&hashes));
+    }
+
+    #[tokio::test]
+    async fn FileHandler_resume_keeps_downloaded_packets() {
+        let content = "ABCDabcdXY".as_bytes();
+        let filename = ".testfiles/FileHandler_resume_keeps_downloaded_packets";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, content, 4);
+        let metainfo = Metainfo::new("tcp://127.0.0.1:1111", "resume", 4, hashes, 10).unwrap();
+        let _ = std::fs::remove_file(format!("{filename}.progress"));
+
+        let handler = TorrentFile::resume(filename, &metainfo).await.unwrap();
+        handler.write_packets(1, &content[4..8]).await.unwrap();
+        handler.save_progress_to_file().await.unwrap();
+        drop(handler);
+
+        let resumed = TorrentFile::resume(filename, &metainfo).await.unwrap();
+        assert_eq!(resumed.bytes_left().await, 6);
+        assert_eq!(resumed.read_packets(1, 1).await.unwrap(), &content[4..8]);
+
+        let other = Metainfo::new(
+            "tcp://127.0.0.1:1111",
+            "other",
+            2,
+            PacketHashes::compute(HashAlgorithm::Sha1, content, 2),
+            10,
+        )
+        .unwrap();
+        let err = TorrentFile::resume(filename, &other).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn FileHandler_resume_rejects_inconsistent_progress() {
+        let content = "ABCDabcdXY".as_bytes();
+        let filename = ".testfiles/FileHandler_resume_rejects_inconsistent_progress";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, content, 4);
+        let metainfo = Metainfo::new("tcp://127.0.0.1:1111", "resume", 4, hashes, 10).unwrap();
+        let _ = std::fs::remove_file(format!("{filename}.progress"));
+        let handler = TorrentFile::resume(filename, &metainfo).await.unwrap();
+        handler.save_progress_to_file().await.unwrap();
+        let progress: serde_json::Value = serde_json::to_value(&handler).unwrap();
+        drop(handler);
+
+        // Availability of fewer packets than there are, and a packet count not matching the size
+        let mut short_availability = progress.clone();
+        short_availability["packet_availability"] =
+            serde_json::to_value(BitVec::from_elem(2, true)).unwrap();
+        let mut wrong_count = progress.clone();
+        wrong_count["packet_count"] = 2.into();
+        wrong_count["packet_availability"] =
+            serde_json::to_value(BitVec::from_elem(2, true)).unwrap();
+
+        for progress in [short_availability, wrong_count] {
+            std::fs::write(
+                format!("{filename}.progress"),
+                serde_json::to_vec(&progress).unwrap(),
+            )
+            .unwrap();
+            let err = TorrentFile::resume(filename, &metainfo)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn FileHandler_recheck_available_packets() {
+        let content = "ABCDabcdXY".as_bytes();
+        let filename = ".testfiles/FileHandler_recheck_available_packets";
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, content, 4);
+        let handler = TorrentFile::new(filename, 10, 4)
+            .unwrap()
+            .with_packet_hashes(hashes)
+            .unwrap();
+        handler.write_packets(0, &content[..8]).await.unwrap();
+        assert_eq!(handler.recheck_available_packets().await.unwrap(), 0);
+
+        // Corrupts the second packet behind the handler's back
+        let mut data = std::fs::read(filename).unwrap();
+        data[5] = b'x';
+        std::fs::write(filename, data).unwrap();
+
+        assert_eq!(handler.recheck_available_packets().await.unwrap(), 1);
+        let mut expected = BitVec::from_elem(3, false);
+        expected.set(0, true);
+        assert_eq!(handler.read_packet_availability().await, expected);
+
+        let unhashed = TorrentFile::new(filename, 10, 4).unwrap();
+        assert!(unhashed.recheck_available_packets().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn FileHandler_recheck_reports_progress() {
+        let content = "ABCDabcdXY".as_bytes();
+        let root = ".testfiles/FileHandler_recheck_reports_progress";
+        let files = vec![
+            FileEntry {
+                path: "a".into(),
+                length: 6,
+            },
+            FileEntry {
+                path: "b".into(),
+                length: 4,
+            },
+        ];
+        let hashes = PacketHashes::compute(HashAlgorithm::Sha1, content, 4);
+        let metainfo =
+            Metainfo::new_multi_file("tcp://127.0.0.1:1111", "recheck", 4, hashes, files).unwrap();
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root).unwrap();
+        // Only the first file is there
+        std::fs::write(format!("{root}/a"), &content[..6]).unwrap();
+
+        let handler = TorrentFile::from_existing(root, &metainfo).unwrap();
+        assert!(handler.read_packet_availability().await.none());
+
+        let mut checked = vec![];
+        let available = handler.recheck(|i| checked.push(i)).await.unwrap();
+        assert_eq!(available, 1);
+        assert_eq!(checked, vec![1, 2, 3]);
+        assert_eq!(handler.bytes_left().await, 6);
+        assert_eq!(std::fs::read(format!("{root}/b")).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn FileHandler_write_packets_skips_available() {
+        let filename = ".testfiles/FileHandler_write_packets_skips_available";
+        let handler = TorrentFile::new(filename, 8, 4).unwrap();
+        handler.write_packets(1, "abcd".as_bytes()).await.unwrap();
+
+        // Changing the file on disk shows whether the packet was written again
+        let mut file = StdFile::options().write(true).open(filename).unwrap();
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(4)).unwrap();
+        std::io::Write::write_all(&mut file, "efgh".as_bytes()).unwrap();
+
+        handler
+            .write_packets(0, "ABCDabcd".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(filename).unwrap(), "ABCDefgh".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn FileHandler_wait_for_packet() {
+        let filename = ".testfiles/FileHandler_wait_for_packet";
+        let handler = Arc::new(TorrentFile::new(filename, 8, 4).unwrap());
+        let waiting = tokio::spawn({
+            let handler = Arc::clone(&handler);
+            async move { handler.wait_for_packet(1).await }
+        });
+
+        handler.write_packets(0, "ABCD".as_bytes()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        handler.write_packets(1, "abcd".as_bytes()).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_millis(100), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn FileHandler_file_range() {
+        let root = ".testfiles/FileHandler_file_range";
+        let files = vec![
+            FileEntry {
+                path: "a".into(),
+                length: 3,
+            },
+            FileEntry {
+                path: "sub/b".into(),
+                length: 7,
+            },
+        ];
+        let handler = TorrentFile::new_multi_file(root, files, 4).unwrap();
+        assert_eq!(handler.file_range(Path::new("sub/b")), Some((3, 7)));
+        assert_eq!(handler.file_range(Path::new("b")), None);
+
+        let single = TorrentFile::new(".testfiles/FileHandler_file_range_single", 10, 4).unwrap();
+        assert_eq!(single.file_range(Path::new("anything")), Some((0, 10)));
+    }
 }
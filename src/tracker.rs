@@ -1,26 +1,209 @@
-use std::net::SocketAddr;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use tokio::io::AsyncBufReadExt;
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use tokio::fs;
 use tokio::io::BufReader;
 use tokio::io::{self, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::net::ToSocketAddrs;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::select;
-use tokio::sync::oneshot;
+use tokio::sync::{self, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use crate::http_tracker::{
+    failure_response, http_response, parse_scrape_query, read_line, scrape_response, AnnounceEvent,
+    AnnounceRequest, AnnounceResponse, AnnouncedPeer, RequestHead,
+};
+use crate::metainfo::InfoHash;
+use crate::peer_wire::PeerId;
+use crate::requests::{RequestToTracker, ScrapeStats, TrackerResponse};
+use crate::udp_tracker::{UdpRequest, UdpResponse, MAX_PACKET_LEN};
 
-use crate::requests::{RequestToTracker, TrackerResponse};
+/// Interval between announces peers are asked to keep
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Connections that send nothing for this long are closed
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+/// Connections sending longer newline-delimited requests are closed
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// How long listeners wait after running out of file descriptors before accepting again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// `EMFILE` and `ENFILE`, the same on Linux, macOS and the BSDs
+const TOO_MANY_OPEN_FILES: [i32; 2] = [24, 23];
+/// Peers returned to requests which don't specify how many they want
+const DEFAULT_NUMWANT: usize = 50;
+/// Keeps UDP responses listing IPv6 peers within `MAX_PACKET_LEN`
+const MAX_NUMWANT: usize = 100;
+/// How often the state is saved, when the tracker has a state file
+const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct Tracker {
-    peerlist: Vec<SocketAddr>,
+    /// Shared by all connections
+    swarms: Arc<Mutex<Swarms>>,
+    read_timeout: Duration,
+    max_connections: usize,
+    /// Key of the connection ids handed out to UDP clients
+    udp_secret: [u8; 16],
+    /// Swarms are saved there periodically and on shutdown, if set
+    state_file: Option<String>,
+    save_interval: Duration,
+    /// Keeps the TCP and UDP listeners from writing the state file at the same time
+    save_lock: sync::Mutex<()>,
+}
+
+/// State of all tracked torrents
+struct Swarms {
+    /// Peers of every torrent
+    peers: HashMap<InfoHash, HashMap<SocketAddr, Peer>>,
+    /// Downloads reported as finished, per torrent
+    completed: HashMap<InfoHash, u32>,
+    announce_interval: Duration,
+}
+
+struct Peer {
+    /// Time the peer last registered
+    last_seen: Instant,
+    /// Known only for peers announcing over HTTP or UDP
+    peer_id: Option<PeerId>,
+    /// Bytes the peer still has to download
+    left: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+/// Contents of a tracker's state file
+struct TrackerState {
+    swarms: Vec<SwarmState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwarmState {
+    info_hash: InfoHash,
+    completed: u32,
+    peers: Vec<PeerState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerState {
+    address: SocketAddr,
+    peer_id: Option<PeerId>,
+    left: u64,
+    /// Seconds since the Unix epoch, as instants can't outlive the process
+    last_seen: u64,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tracker {
     pub fn new() -> Self {
-        Self { peerlist: vec![] }
+        Self {
+            swarms: Arc::new(Mutex::new(Swarms {
+                peers: HashMap::new(),
+                completed: HashMap::new(),
+                announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            })),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            udp_secret: rand::random(),
+            state_file: None,
+            save_interval: DEFAULT_SAVE_INTERVAL,
+            save_lock: sync::Mutex::new(()),
+        }
+    }
+
+    /// Creates a tracker keeping its state in `path`, restoring the swarms saved there if it exists
+    ///
+    /// Peers which weren't seen for two announce intervals by then are dropped
+    pub async fn from_state_file(path: &str) -> io::Result<Self> {
+        let tracker = Self {
+            state_file: Some(path.to_owned()),
+            ..Self::new()
+        };
+        match fs::read(path).await {
+            Ok(state) => tracker
+                .swarms
+                .lock()
+                .unwrap()
+                .restore(serde_json::from_slice(&state)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(tracker)
+    }
+
+    /// Saves the swarms to the state file, if the tracker has one
+    ///
+    /// The previous state is only replaced once the new one is fully written
+    pub async fn save_state(&self) -> io::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let state = serde_json::to_vec(&self.swarms.lock().unwrap().snapshot())?;
+
+        let _lock = self.save_lock.lock().await;
+        let temp_path = format!("{path}.tmp");
+        // Synced before the rename, so that a crash leaves either snapshot whole
+        let mut file = fs::File::create(&temp_path).await?;
+        file.write_all(&state).await?;
+        file.sync_all().await?;
+        fs::rename(temp_path, path).await
+    }
+
+    /// Saves the state every `save_interval`, never returning
+    async fn save_periodically(&self) -> io::Result<()> {
+        if self.state_file.is_none() {
+            return std::future::pending().await;
+        }
+
+        let mut interval = time::interval(self.save_interval);
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.save_state().await {
+                println!("Couldn't save tracker state: {err}");
+            }
+        }
+    }
+
+    /// Peers which don't register again within two `announce_interval`s are dropped
+    pub fn with_announce_interval(self, announce_interval: Duration) -> Self {
+        self.swarms.lock().unwrap().announce_interval = announce_interval;
+        self
+    }
+
+    /// Connections idle for longer than `read_timeout` are closed
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Connections above `max_connections` wait until one of the served ones closes
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Only used by trackers created with `from_state_file`
+    pub fn with_save_interval(mut self, save_interval: Duration) -> Self {
+        self.save_interval = save_interval;
+        self
     }
 
     pub async fn listen<T>(
-        &mut self,
+        &self,
         addr: &T,
         shutdown_channel: oneshot::Receiver<()>,
     ) -> io::Result<()>
@@ -28,39 +211,1093 @@ impl Tracker {
         T: ToSocketAddrs,
     {
         select! {
-            res = shutdown_channel => { res.map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string())) },
+            res = shutdown_channel => {
+                res.map_err(|err| io::Error::other(err.to_string()))?;
+                self.save_state().await
+            },
             res = self.do_listen(addr) => { res },
+            res = self.save_periodically() => { res },
         }
     }
 
-    pub async fn do_listen<T>(&mut self, addr: &T) -> io::Result<()>
+    /// Serves every connection in its own task; dropping the future closes all of them
+    pub async fn do_listen<T>(&self, addr: &T) -> io::Result<()>
     where
         T: ToSocketAddrs,
     {
         let listener = TcpListener::bind(addr).await?;
-        while let Ok((mut stream, _)) = listener.accept().await {
-            let (reader, mut writer) = stream.split();
-            let reader = BufReader::new(reader);
-
-            let mut lines = reader.lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                let response = {
-                    match serde_json::from_str::<RequestToTracker>(&line) {
-                        Ok(RequestToTracker::GetPeers) => {
-                            TrackerResponse::Peers(self.peerlist.clone())
-                        }
-                        Ok(RequestToTracker::RegisterAsPeer(client_addr)) => {
-                            self.peerlist.push(client_addr);
-                            TrackerResponse::RegisteredSuccesfully
-                        }
-
-                        _ => TrackerResponse::InvalidRequest,
-                    }
+        let connection_slots = Arc::new(Semaphore::new(self.max_connections));
+        let mut connections = JoinSet::new();
+
+        loop {
+            let (stream, slot) = select! {
+                res = accept_with_slot(&listener, &connection_slots) => res?,
+                // Reaps finished connections
+                Some(_) = connections.join_next() => continue,
+            };
+
+            let swarms = Arc::clone(&self.swarms);
+            let read_timeout = self.read_timeout;
+            connections.spawn(async move {
+                let _slot = slot;
+                handle_connection(stream, swarms, read_timeout).await
+            });
+        }
+    }
+
+    /// Like `listen`, but for UDP tracker requests (BEP 15)
+    pub async fn listen_udp<T>(
+        &self,
+        addr: &T,
+        shutdown_channel: oneshot::Receiver<()>,
+    ) -> io::Result<()>
+    where
+        T: ToSocketAddrs,
+    {
+        select! {
+            res = shutdown_channel => {
+                res.map_err(|err| io::Error::other(err.to_string()))?;
+                self.save_state().await
+            },
+            res = self.do_listen_udp(addr) => { res },
+            res = self.save_periodically() => { res },
+        }
+    }
+
+    /// Answers every datagram right away, as no request needs to wait for anything
+    pub async fn do_listen_udp<T>(&self, addr: &T) -> io::Result<()>
+    where
+        T: ToSocketAddrs,
+    {
+        let socket = UdpSocket::bind(addr).await?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+
+        loop {
+            // Errors concern single datagrams (e.g. an unreachable client), not the socket
+            let Ok((len, client_addr)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            if let Some(response) = self.handle_udp_request(&buf[..len], client_addr) {
+                let _ = socket
+                    .send_to(&response.to_bytes(client_addr.is_ipv6()), client_addr)
+                    .await;
+            }
+        }
+    }
+
+    /// Returns `None` for malformed requests, which can't be answered without a transaction id
+    fn handle_udp_request(&self, packet: &[u8], client_addr: SocketAddr) -> Option<UdpResponse> {
+        let request = UdpRequest::from_bytes(packet).ok()?;
+        let minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+
+        let (connection_id, transaction_id) = match &request {
+            UdpRequest::Connect { transaction_id } => {
+                return Some(UdpResponse::Connect {
+                    transaction_id: *transaction_id,
+                    connection_id: self.connection_id(client_addr, minute),
+                })
+            }
+            UdpRequest::Announce {
+                connection_id,
+                transaction_id,
+                ..
+            }
+            | UdpRequest::Scrape {
+                connection_id,
+                transaction_id,
+                ..
+            } => (*connection_id, *transaction_id),
+        };
+        // Connection ids stay valid for one to two minutes
+        if connection_id != self.connection_id(client_addr, minute)
+            && connection_id != self.connection_id(client_addr, minute.saturating_sub(1))
+        {
+            return Some(UdpResponse::Error {
+                transaction_id,
+                message: "Invalid connection id.".to_owned(),
+            });
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        match request {
+            UdpRequest::Connect { .. } => unreachable!(),
+            UdpRequest::Announce { request, .. } => Some(UdpResponse::Announce {
+                transaction_id,
+                response: swarms.handle_announce(&request, client_addr.ip()),
+            }),
+            UdpRequest::Scrape { info_hashes, .. } => {
+                swarms.drop_expired_peers();
+                Some(UdpResponse::Scrape {
+                    transaction_id,
+                    stats: info_hashes
+                        .iter()
+                        .map(|info_hash| swarms.stats(info_hash))
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    /// Connection id of `client_addr` during `minute`, which the tracker doesn't need to store
+    fn connection_id(&self, client_addr: SocketAddr, minute: u64) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(self.udp_secret);
+        hasher.update(client_addr.to_string());
+        hasher.update(minute.to_be_bytes());
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+}
+
+/// Accepts the next connection on `listener`
+///
+/// Failing to accept a single connection (e.g. aborted by the peer, or with no file descriptors
+/// left) doesn't stop the listener, the error is printed and accepting continues
+pub async fn accept(listener: &TcpListener) -> TcpStream {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(err) => {
+                println!("Couldn't accept a connection: {err}");
+                // Retrying right away would fail again until some connection is closed
+                if err
+                    .raw_os_error()
+                    .is_some_and(|code| TOO_MANY_OPEN_FILES.contains(&code))
+                {
+                    time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+/// Waits for one of `connection_slots` to be free, then for a connection to take it
+pub async fn accept_with_slot(
+    listener: &TcpListener,
+    connection_slots: &Arc<Semaphore>,
+) -> io::Result<(TcpStream, OwnedSemaphorePermit)> {
+    let slot = Arc::clone(connection_slots)
+        .acquire_owned()
+        .await
+        .map_err(io::Error::other)?;
+    Ok((accept(listener).await, slot))
+}
+
+/// Answers newline-delimited requests, with newline-terminated responses, until the peer disconnects or stays idle for `read_timeout`
+///
+/// Connections starting with an HTTP `GET` request get a single HTTP response instead.
+/// Requests longer than `MAX_REQUEST_LEN` close the connection, rather than being buffered
+async fn handle_connection(
+    mut stream: TcpStream,
+    swarms: Arc<Mutex<Swarms>>,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    let peer_ip = stream.peer_addr()?.ip();
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+
+    let mut first_line = true;
+    while let Ok(Ok(Some(line))) =
+        time::timeout(read_timeout, read_line(&mut reader, MAX_REQUEST_LEN)).await
+    {
+        if first_line && line.starts_with("GET ") {
+            return handle_http_request(&line, reader, writer, &swarms, peer_ip, read_timeout)
+                .await;
+        }
+        first_line = false;
+
+        let response = match serde_json::from_str::<RequestToTracker>(&line) {
+            Ok(request) => swarms.lock().unwrap().handle_request(request, peer_ip),
+            Err(_) => TrackerResponse::InvalidRequest,
+        };
+
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+/// Answers an HTTP announce or scrape, whose `request_line` was already read, with a bencoded
+/// response
+async fn handle_http_request(
+    request_line: &str,
+    mut reader: BufReader<ReadHalf<'_>>,
+    mut writer: WriteHalf<'_>,
+    swarms: &Mutex<Swarms>,
+    peer_ip: IpAddr,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    // Headers aren't needed, but the whole request has to arrive before responding
+    let Ok(head) =
+        RequestHead::read_after_request_line(request_line, &mut reader, read_timeout).await
+    else {
+        return Ok(());
+    };
+
+    let body = match head.path() {
+        "/announce" => match AnnounceRequest::from_query(head.query()) {
+            Ok(request) => swarms
+                .lock()
+                .unwrap()
+                .handle_announce(&request, peer_ip)
+                .to_bencode(request.compact),
+            Err(err) => failure_response(&err.to_string()),
+        },
+        "/scrape" => match parse_scrape_query(head.query()) {
+            Ok(info_hashes) => {
+                let mut swarms = swarms.lock().unwrap();
+                swarms.drop_expired_peers();
+                let files: Vec<_> = info_hashes
+                    .into_iter()
+                    .map(|info_hash| (info_hash, swarms.stats(&info_hash)))
+                    .collect();
+                scrape_response(&files)
+            }
+            Err(err) => failure_response(&err.to_string()),
+        },
+        _ => failure_response("Unknown path."),
+    };
+
+    writer.write_all(&http_response(&body)).await?;
+    writer.shutdown().await
+}
+
+impl Swarms {
+    /// Updates swarms according to `request` from `peer_ip` and returns the response for the
+    /// requesting peer
+    fn handle_request(&mut self, request: RequestToTracker, peer_ip: IpAddr) -> TrackerResponse {
+        self.drop_expired_peers();
+
+        // Peers can only speak for themselves, as with announces
+        let announced_addr =
+            |addr: SocketAddr| SocketAddr::new(announced_ip(Some(addr.ip()), peer_ip), addr.port());
+
+        match request {
+            RequestToTracker::GetPeers(info_hash, numwant) => TrackerResponse::Peers(sample(
+                self.peers
+                    .get(&info_hash)
+                    .into_iter()
+                    .flat_map(|swarm| swarm.keys().copied()),
+                numwant,
+            )),
+            RequestToTracker::RegisterAsPeer(info_hash, client_addr, left, _, _) => {
+                self.register(info_hash, announced_addr(client_addr), None, left);
+                TrackerResponse::RegisteredSuccesfully(self.announce_interval)
+            }
+            RequestToTracker::Deregister(info_hash, client_addr) => {
+                self.deregister(info_hash, announced_addr(client_addr));
+                TrackerResponse::Deregistered
+            }
+            RequestToTracker::ReportCompleted(info_hash, client_addr) => {
+                self.complete(info_hash, announced_addr(client_addr));
+                TrackerResponse::CompletionRecorded
+            }
+            RequestToTracker::Scrape(info_hashes) => TrackerResponse::Stats(
+                info_hashes
+                    .iter()
+                    .map(|info_hash| self.stats(info_hash))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Handles an HTTP or UDP announce from `peer_ip`, returning the swarm's other peers
+    fn handle_announce(&mut self, request: &AnnounceRequest, peer_ip: IpAddr) -> AnnounceResponse {
+        self.drop_expired_peers();
+
+        let client_addr = SocketAddr::new(announced_ip(request.ip, peer_ip), request.port);
+        if request.event == Some(AnnounceEvent::Stopped) {
+            self.deregister(request.info_hash, client_addr);
+        } else {
+            // Before the peer is registered as a seeder, which it wouldn't count for
+            if request.event == Some(AnnounceEvent::Completed) {
+                self.complete(request.info_hash, client_addr);
+            }
+            self.register(
+                request.info_hash,
+                client_addr,
+                Some(request.peer_id),
+                request.left,
+            );
+        }
+
+        let peers = sample(
+            self.peers
+                .get(&request.info_hash)
+                .into_iter()
+                .flatten()
+                .filter(|(address, _)| **address != client_addr)
+                .map(|(address, peer)| AnnouncedPeer {
+                    address: *address,
+                    peer_id: peer.peer_id,
+                }),
+            request.numwant,
+        );
+        let stats = self.stats(&request.info_hash);
+        AnnounceResponse {
+            interval: self.announce_interval,
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            peers,
+        }
+    }
+
+    fn stats(&self, info_hash: &InfoHash) -> ScrapeStats {
+        let completed = self.completed.get(info_hash).copied().unwrap_or(0);
+        let Some(swarm) = self.peers.get(info_hash) else {
+            return ScrapeStats {
+                completed,
+                ..Default::default()
+            };
+        };
+
+        let seeders = swarm.values().filter(|peer| peer.left == 0).count() as u32;
+        ScrapeStats {
+            seeders,
+            completed,
+            leechers: swarm.len() as u32 - seeders,
+        }
+    }
+
+    /// Adds a new peer or refreshes an already registered one
+    fn register(
+        &mut self,
+        info_hash: InfoHash,
+        client_addr: SocketAddr,
+        peer_id: Option<PeerId>,
+        left: u64,
+    ) {
+        let peer = self
+            .peers
+            .entry(info_hash)
+            .or_default()
+            .entry(client_addr)
+            .or_insert(Peer {
+                last_seen: Instant::now(),
+                peer_id,
+                left,
+            });
+        peer.last_seen = Instant::now();
+        peer.peer_id = peer_id.or(peer.peer_id);
+        peer.left = left;
+    }
+
+    /// Counts a finished download, turning the peer into a seeder if it's registered
+    ///
+    /// Peers registered as seeders already aren't counted again, so that repeated reports
+    /// can't inflate the count
+    fn complete(&mut self, info_hash: InfoHash, client_addr: SocketAddr) {
+        let peer = self
+            .peers
+            .get_mut(&info_hash)
+            .and_then(|swarm| swarm.get_mut(&client_addr));
+        match peer {
+            Some(peer) if peer.left == 0 => return,
+            Some(peer) => peer.left = 0,
+            None => {}
+        }
+        *self.completed.entry(info_hash).or_default() += 1;
+    }
+
+    fn deregister(&mut self, info_hash: InfoHash, client_addr: SocketAddr) {
+        if let Some(swarm) = self.peers.get_mut(&info_hash) {
+            swarm.remove(&client_addr);
+        }
+    }
+
+    fn snapshot(&self) -> TrackerState {
+        let now = SystemTime::now();
+        let mut info_hashes: Vec<_> = self.peers.keys().chain(self.completed.keys()).collect();
+        info_hashes.sort();
+        info_hashes.dedup();
+
+        let swarms = info_hashes
+            .into_iter()
+            .map(|info_hash| SwarmState {
+                info_hash: *info_hash,
+                completed: self.completed.get(info_hash).copied().unwrap_or(0),
+                peers: self
+                    .peers
+                    .get(info_hash)
+                    .into_iter()
+                    .flatten()
+                    .map(|(address, peer)| PeerState {
+                        address: *address,
+                        peer_id: peer.peer_id,
+                        left: peer.left,
+                        last_seen: (now - peer.last_seen.elapsed())
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        TrackerState { swarms }
+    }
+
+    /// Adds the swarms of a snapshot, aging its peers by the time passed since they were last seen
+    fn restore(&mut self, state: TrackerState) {
+        let now = SystemTime::now();
+        for swarm in state.swarms {
+            *self.completed.entry(swarm.info_hash).or_default() += swarm.completed;
+            for peer in swarm.peers {
+                let age = now
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(peer.last_seen))
+                    .unwrap_or_default();
+                // Too old to be represented, so certainly expired
+                let Some(last_seen) = Instant::now().checked_sub(age) else {
+                    continue;
                 };
+                self.peers.entry(swarm.info_hash).or_default().insert(
+                    peer.address,
+                    Peer {
+                        last_seen,
+                        peer_id: peer.peer_id,
+                        left: peer.left,
+                    },
+                );
+            }
+        }
+        self.drop_expired_peers();
+    }
+
+    /// Removes peers that haven't registered for two announce intervals, and swarms left empty
+    fn drop_expired_peers(&mut self) {
+        let peer_timeout = 2 * self.announce_interval;
+        for swarm in self.peers.values_mut() {
+            swarm.retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+        }
+        self.peers.retain(|_, swarm| !swarm.is_empty());
+    }
+}
+
+/// Address registered for a peer announcing from `peer_ip` and claiming to be at `claimed_ip`
+///
+/// Claims are only trusted from loopback and private addresses, e.g. peers sharing the tracker's
+/// network, so that nobody can make the swarm connect to a third party. Unspecified and
+/// multicast claims can't be connected to, so they're ignored too
+fn announced_ip(claimed_ip: Option<IpAddr>, peer_ip: IpAddr) -> IpAddr {
+    let trusted = match peer_ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local(),
+    };
+    match claimed_ip {
+        Some(claimed_ip)
+            if trusted && !claimed_ip.is_unspecified() && !claimed_ip.is_multicast() =>
+        {
+            claimed_ip
+        }
+        _ => peer_ip,
+    }
+}
+
+/// Picks up to `numwant` peers at random, `DEFAULT_NUMWANT` if unspecified and never more than
+/// `MAX_NUMWANT`
+fn sample<T>(peers: impl Iterator<Item = T>, numwant: Option<u32>) -> Vec<T> {
+    let amount = numwant.map_or(DEFAULT_NUMWANT, |numwant| {
+        min(numwant as usize, MAX_NUMWANT)
+    });
+    peers.choose_multiple(&mut rand::thread_rng(), amount)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+    use crate::http_tracker;
+    use crate::udp_tracker::UdpTrackerClient;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use tokio::io::AsyncReadExt;
 
-                writer.write_all(&serde_json::to_vec(&response)?).await?;
+    fn handle_request(tracker: &Tracker, request: RequestToTracker) -> TrackerResponse {
+        tracker
+            .swarms
+            .lock()
+            .unwrap()
+            .handle_request(request, IpAddr::from([127, 0, 0, 1]))
+    }
+
+    fn peers(tracker: &Tracker, info_hash: InfoHash) -> Vec<SocketAddr> {
+        match handle_request(tracker, RequestToTracker::GetPeers(info_hash, None)) {
+            TrackerResponse::Peers(peers) => peers,
+            _ => panic!("Expected a peerlist"),
+        }
+    }
+
+    #[test]
+    fn Tracker_separate_swarms() {
+        let tracker = Tracker::new();
+        let peer_a = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let peer_b = SocketAddr::from_str("127.0.0.1:2000").unwrap();
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], peer_a, 0, 0, 0),
+        );
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([2; 20], peer_b, 0, 0, 0),
+        );
+
+        assert_eq!(peers(&tracker, [1; 20]), vec![peer_a]);
+        assert_eq!(peers(&tracker, [2; 20]), vec![peer_b]);
+        assert!(peers(&tracker, [3; 20]).is_empty());
+    }
+
+    #[test]
+    fn Tracker_no_duplicate_peers() {
+        let tracker = Tracker::new();
+        let peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], peer, 0, 0, 0),
+        );
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], peer, 0, 0, 0),
+        );
+
+        assert_eq!(peers(&tracker, [1; 20]), vec![peer]);
+    }
+
+    #[test]
+    fn Tracker_samples_numwant_peers() {
+        let tracker = Tracker::new();
+        for port in 1000..1300 {
+            let peer = SocketAddr::from(([127, 0, 0, 1], port));
+            handle_request(
+                &tracker,
+                RequestToTracker::RegisterAsPeer([1; 20], peer, 0, 0, 0),
+            );
+        }
+        let get_peers = |numwant| match handle_request(
+            &tracker,
+            RequestToTracker::GetPeers([1; 20], numwant),
+        ) {
+            TrackerResponse::Peers(peers) => peers,
+            _ => panic!("Expected a peerlist"),
+        };
+
+        let peers = get_peers(None);
+        assert_eq!(peers.len(), DEFAULT_NUMWANT);
+        assert_eq!(peers.iter().collect::<HashSet<_>>().len(), DEFAULT_NUMWANT);
+        assert_eq!(get_peers(Some(10)).len(), 10);
+        assert_eq!(get_peers(Some(1000)).len(), MAX_NUMWANT);
+        // Samples are random, so two of them are very unlikely to be the same
+        assert_ne!(
+            get_peers(None).into_iter().collect::<HashSet<_>>(),
+            peers.into_iter().collect::<HashSet<_>>()
+        );
+
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let response = tracker.swarms.lock().unwrap().handle_announce(
+            &AnnounceRequest {
+                info_hash: [1; 20],
+                peer_id: [1; 20],
+                ip: None,
+                port: client_addr.port(),
+                uploaded: 0,
+                downloaded: 0,
+                left: 0,
+                event: None,
+                numwant: Some(299),
+                compact: true,
+            },
+            client_addr.ip(),
+        );
+        assert_eq!(response.peers.len(), MAX_NUMWANT);
+        assert!(response
+            .peers
+            .iter()
+            .all(|peer| peer.address != client_addr));
+    }
+
+    #[test]
+    fn Tracker_scrape_counts_completed_downloads() {
+        let tracker = Tracker::new();
+        let seed = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let leech = SocketAddr::from_str("127.0.0.1:2000").unwrap();
+        let stats =
+            || match handle_request(&tracker, RequestToTracker::Scrape(vec![[1; 20], [2; 20]])) {
+                TrackerResponse::Stats(stats) => stats,
+                _ => panic!("Expected stats"),
+            };
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], seed, 0, 0, 0),
+        );
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], leech, 10, 0, 0),
+        );
+        assert_eq!(
+            stats(),
+            vec![
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 0,
+                    leechers: 1,
+                },
+                ScrapeStats::default(),
+            ]
+        );
+
+        assert!(matches!(
+            handle_request(&tracker, RequestToTracker::ReportCompleted([1; 20], leech)),
+            TrackerResponse::CompletionRecorded
+        ));
+        // Seeders reporting again aren't counted
+        handle_request(&tracker, RequestToTracker::ReportCompleted([1; 20], leech));
+        handle_request(&tracker, RequestToTracker::ReportCompleted([1; 20], seed));
+        assert_eq!(
+            stats()[0],
+            ScrapeStats {
+                seeders: 2,
+                completed: 1,
+                leechers: 0,
             }
+        );
+
+        // Finished downloads are still counted once their peers leave
+        handle_request(&tracker, RequestToTracker::Deregister([1; 20], seed));
+        handle_request(&tracker, RequestToTracker::Deregister([1; 20], leech));
+        assert_eq!(
+            stats()[0],
+            ScrapeStats {
+                seeders: 0,
+                completed: 1,
+                leechers: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn Tracker_deregister() {
+        let tracker = Tracker::new();
+        let peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], peer, 0, 0, 0),
+        );
+        handle_request(&tracker, RequestToTracker::Deregister([1; 20], peer));
+
+        assert!(peers(&tracker, [1; 20]).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn Tracker_expires_peers() {
+        let announce_interval = Duration::from_secs(60);
+        let tracker = Tracker::new().with_announce_interval(announce_interval);
+        let stale_peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let fresh_peer = SocketAddr::from_str("127.0.0.1:2000").unwrap();
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], stale_peer, 0, 0, 0),
+        );
+        time::advance(announce_interval).await;
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], fresh_peer, 0, 0, 0),
+        );
+        // The stale peer last registered just over two intervals ago
+        time::advance(announce_interval + Duration::from_secs(1)).await;
+
+        assert_eq!(peers(&tracker, [1; 20]), vec![fresh_peer]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn Tracker_restores_saved_state() {
+        let path = ".testfiles/Tracker_restores_saved_state";
+        let _ = std::fs::remove_file(path);
+        let peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let stale_peer = SocketAddr::from_str("127.0.0.1:2000").unwrap();
+
+        let tracker = Tracker::from_state_file(path).await.unwrap();
+        assert!(peers(&tracker, [1; 20]).is_empty());
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], stale_peer, 10, 0, 0),
+        );
+        time::advance(3 * DEFAULT_ANNOUNCE_INTERVAL).await;
+        // Requests would drop the stale peer before it's saved
+        {
+            let mut swarms = tracker.swarms.lock().unwrap();
+            swarms.register([1; 20], peer, None, 10);
+            swarms.complete([1; 20], peer);
+            swarms.complete([2; 20], peer);
+        }
+        assert_eq!(tracker.swarms.lock().unwrap().peers[&[1; 20]].len(), 2);
+        tracker.save_state().await.unwrap();
+
+        let restored = Tracker::from_state_file(path).await.unwrap();
+        assert_eq!(peers(&restored, [1; 20]), vec![peer]);
+        let stats =
+            match handle_request(&restored, RequestToTracker::Scrape(vec![[1; 20], [2; 20]])) {
+                TrackerResponse::Stats(stats) => stats,
+                _ => panic!("Expected stats"),
+            };
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 1,
+                    leechers: 0,
+                },
+                ScrapeStats {
+                    seeders: 0,
+                    completed: 1,
+                    leechers: 0,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn Tracker_saves_state_periodically_and_on_shutdown() {
+        let path = ".testfiles/Tracker_saves_state_periodically_and_on_shutdown";
+        let _ = std::fs::remove_file(path);
+        let addr = SocketAddr::from_str("127.0.0.1:46006").unwrap();
+        let peer = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+
+        let tracker = Arc::new(
+            Tracker::from_state_file(path)
+                .await
+                .unwrap()
+                .with_save_interval(Duration::from_millis(50)),
+        );
+        let (shutdown_wx, shutdown_rx) = oneshot::channel();
+        let handle = tokio::spawn({
+            let tracker = Arc::clone(&tracker);
+            async move { tracker.listen_udp(&addr, shutdown_rx).await }
+        });
+
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], peer, 0, 0, 0),
+        );
+        time::sleep(Duration::from_millis(120)).await;
+        let saved = Tracker::from_state_file(path).await.unwrap();
+        assert_eq!(peers(&saved, [1; 20]), vec![peer]);
+
+        handle_request(&tracker, RequestToTracker::Deregister([1; 20], peer));
+        shutdown_wx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        let saved = Tracker::from_state_file(path).await.unwrap();
+        assert!(peers(&saved, [1; 20]).is_empty());
+    }
+
+    #[test]
+    fn Tracker_announce_lists_other_peers() {
+        let tracker = Tracker::new();
+        let ip = IpAddr::from_str("127.0.0.1").unwrap();
+        let announce = |port, event| AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [port as u8; 20],
+            ip: None,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event,
+            numwant: None,
+            compact: false,
+        };
+        let mut swarms = tracker.swarms.lock().unwrap();
+
+        let response = swarms.handle_announce(&announce(1000, None), ip);
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval, DEFAULT_ANNOUNCE_INTERVAL);
+
+        let response = swarms.handle_announce(&announce(2000, Some(AnnounceEvent::Started)), ip);
+        assert_eq!(
+            response.peers,
+            vec![AnnouncedPeer {
+                address: SocketAddr::new(ip, 1000),
+                peer_id: Some([(1000 % 256) as u8; 20]),
+            }]
+        );
+
+        swarms.handle_announce(&announce(1000, Some(AnnounceEvent::Stopped)), ip);
+        let response = swarms.handle_announce(&announce(2000, None), ip);
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn Tracker_registers_claimed_ip_only_from_private_addresses() {
+        let tracker = Tracker::new();
+        let victim = IpAddr::from_str("203.0.113.7").unwrap();
+        let announce = |ip| AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [1; 20],
+            ip,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            numwant: None,
+            compact: false,
+        };
+        let mut swarms = tracker.swarms.lock().unwrap();
+
+        let public_ip = IpAddr::from_str("198.51.100.1").unwrap();
+        swarms.handle_announce(&announce(Some(victim)), public_ip);
+        let private_ip = IpAddr::from_str("192.168.1.10").unwrap();
+        swarms.handle_announce(&announce(Some(victim)), private_ip);
+        swarms.handle_announce(&announce(None), private_ip);
+
+        let mut registered: Vec<_> = swarms.peers[&[1; 20]].keys().map(|a| a.ip()).collect();
+        registered.sort();
+        assert_eq!(registered, vec![private_ip, public_ip, victim]);
+    }
+
+    #[test]
+    fn Tracker_ignores_unspecified_and_multicast_claimed_ips() {
+        let tracker = Tracker::new();
+        let announce = |ip, port| AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [1; 20],
+            ip: Some(IpAddr::from_str(ip).unwrap()),
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            numwant: None,
+            compact: false,
+        };
+        let mut swarms = tracker.swarms.lock().unwrap();
+
+        let private_ip = IpAddr::from_str("192.168.1.10").unwrap();
+        swarms.handle_announce(&announce("0.0.0.0", 6881), private_ip);
+        swarms.handle_announce(&announce("::", 6882), private_ip);
+        swarms.handle_announce(&announce("224.0.0.1", 6883), private_ip);
+        swarms.handle_announce(&announce("ff02::1", 6884), private_ip);
+
+        let registered: Vec<_> = swarms.peers[&[1; 20]].keys().map(|a| a.ip()).collect();
+        assert_eq!(registered, vec![private_ip; 4]);
+    }
+
+    #[test]
+    fn Tracker_json_requests_only_affect_the_sender_from_public_addresses() {
+        let tracker = Tracker::new();
+        let victim = SocketAddr::from_str("203.0.113.7:6881").unwrap();
+        let public_ip = IpAddr::from_str("198.51.100.1").unwrap();
+        handle_request(
+            &tracker,
+            RequestToTracker::RegisterAsPeer([1; 20], victim, 0, 0, 0),
+        );
+
+        let mut swarms = tracker.swarms.lock().unwrap();
+        swarms.handle_request(RequestToTracker::Deregister([1; 20], victim), public_ip);
+        swarms.handle_request(
+            RequestToTracker::RegisterAsPeer([1; 20], victim, 0, 0, 0),
+            public_ip,
+        );
+
+        let mut registered: Vec<_> = swarms.peers[&[1; 20]].keys().copied().collect();
+        registered.sort();
+        assert_eq!(registered, vec![SocketAddr::new(public_ip, 6881), victim]);
+    }
+
+    #[tokio::test]
+    async fn Tracker_serves_http_announce() {
+        let addr = SocketAddr::from_str("127.0.0.1:46002").unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { Tracker::new().listen(&addr, shutdown_rx).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // As another client would send it
+        let request =
+            "GET /announce?info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01\
+            &peer_id=-XX0000-000000000000&port=6881&uploaded=0&downloaded=0&left=10\
+            &event=started&compact=0 HTTP/1.1\r\nHost: 127.0.0.1\r\nUser-Agent: test\r\n\r\n";
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with(b"10:incompletei1e8:intervali60e5:peerslee"));
+
+        // Our own client's announce, which lists the peer above in compact form
+        let response = http_tracker::announce(
+            &format!("http://{addr}/announce"),
+            &AnnounceRequest {
+                info_hash: [1; 20],
+                peer_id: [2; 20],
+                ip: None,
+                port: 6882,
+                uploaded: 0,
+                downloaded: 0,
+                left: 0,
+                event: None,
+                numwant: None,
+                compact: true,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.peers,
+            vec![AnnouncedPeer::from(
+                SocketAddr::from_str("127.0.0.1:6881").unwrap()
+            )]
+        );
+
+        let stats = http_tracker::scrape(&format!("http://{addr}/announce"), &[[1; 20], [2; 20]])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 0,
+                    leechers: 1,
+                },
+                ScrapeStats::default(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn Tracker_serves_udp_announce_and_scrape() {
+        let addr = SocketAddr::from_str("127.0.0.1:46003").unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { Tracker::new().listen_udp(&addr, shutdown_rx).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let announce = |port, left| AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            ip: None,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            numwant: None,
+            compact: false,
+        };
+        let mut seed = UdpTrackerClient::new(&addr.to_string()).await.unwrap();
+        let mut leech = UdpTrackerClient::new(&addr.to_string()).await.unwrap();
+
+        seed.announce(&announce(6881, 0)).await.unwrap();
+        let response = leech.announce(&announce(6882, 10)).await.unwrap();
+        assert_eq!((response.seeders, response.leechers), (1, 1));
+        assert_eq!(
+            response.peers,
+            vec![AnnouncedPeer {
+                address: SocketAddr::from_str("127.0.0.1:6881").unwrap(),
+                peer_id: None,
+            }]
+        );
+
+        let stats = leech.scrape(&[[1; 20], [3; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 0,
+                    leechers: 1,
+                },
+                ScrapeStats::default(),
+            ]
+        );
+    }
+
+    #[test]
+    fn Tracker_rejects_unknown_udp_connection_ids() {
+        let tracker = Tracker::new();
+        let client_addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let scrape = |connection_id| UdpRequest::Scrape {
+            connection_id,
+            transaction_id: 1,
+            info_hashes: vec![[1; 20]],
+        };
+
+        let Some(UdpResponse::Connect { connection_id, .. }) = tracker.handle_udp_request(
+            &UdpRequest::Connect { transaction_id: 1 }.to_bytes(),
+            client_addr,
+        ) else {
+            panic!("Expected a connection id");
+        };
+        assert!(matches!(
+            tracker.handle_udp_request(&scrape(connection_id).to_bytes(), client_addr),
+            Some(UdpResponse::Scrape { .. })
+        ));
+
+        // Issued to another client, or made up
+        let other_addr = SocketAddr::from_str("127.0.0.1:2000").unwrap();
+        for (connection_id, client_addr) in [(connection_id, other_addr), (1234, client_addr)] {
+            assert!(matches!(
+                tracker.handle_udp_request(&scrape(connection_id).to_bytes(), client_addr),
+                Some(UdpResponse::Error { .. })
+            ));
         }
-        Ok(())
+    }
+
+    #[tokio::test]
+    async fn Tracker_idle_connection_doesnt_block() {
+        let addr = SocketAddr::from_str("127.0.0.1:46001").unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { Tracker::new().listen(&addr, shutdown_rx).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let _idle = TcpStream::connect(addr).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = serde_json::to_vec(&RequestToTracker::GetPeers([1; 20], None)).unwrap();
+        request.push(b'\n');
+        stream.write_all(&request).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let bytes_read = time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("Tracker didn't respond while another connection was idle")
+            .unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&buf[..bytes_read]).unwrap(),
+            TrackerResponse::Peers(peers) if peers.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn Tracker_closes_connections_sending_overlong_requests() {
+        let addr = SocketAddr::from_str("127.0.0.1:46012").unwrap();
+        let (_shutdown_wx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { Tracker::new().listen(&addr, shutdown_rx).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // A request which never ends, instead of being buffered until memory runs out
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(&vec![b'x'; MAX_REQUEST_LEN + 2])
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let res = time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("Tracker kept reading the request");
+        // Closed, possibly with a reset as the rest of the request is unread
+        assert!(matches!(res, Ok(0) | Err(_)), "{res:?}");
     }
 }
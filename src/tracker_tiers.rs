@@ -0,0 +1,89 @@
+use rand::seq::SliceRandom;
+
+#[derive(Debug, Clone, Default)]
+/// Trackers of a torrent grouped in tiers (BEP 12), tried in order until one responds
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    /// Shuffles every tier, so that clients spread over its trackers
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut tiers: Vec<_> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        Self { tiers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Tracker URLs in the order they should be tried
+    pub fn urls(&self) -> Vec<String> {
+        self.tiers.iter().flatten().cloned().collect()
+    }
+
+    /// Moves `url` to the front of its tier, as the tracker which last responded is tried first
+    pub fn promote(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(i) = tier.iter().position(|tracker| tracker == url) {
+                let tracker = tier.remove(i);
+                tier.insert(0, tracker);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+
+    fn tiers() -> Vec<Vec<String>> {
+        vec![
+            (0..8).map(|i| format!("udp://a{i}:1")).collect(),
+            vec![],
+            vec!["http://b:2/announce".to_owned()],
+        ]
+    }
+
+    #[test]
+    fn TrackerTiers_keeps_tier_order() {
+        let trackers = TrackerTiers::new(tiers());
+        let urls = trackers.urls();
+
+        assert_eq!(urls.len(), 9);
+        assert_eq!(urls[8], "http://b:2/announce");
+        let mut first_tier = urls[..8].to_vec();
+        first_tier.sort();
+        assert_eq!(first_tier, tiers()[0]);
+        assert!(TrackerTiers::new(vec![vec![]]).is_empty());
+    }
+
+    #[test]
+    fn TrackerTiers_shuffles_tiers() {
+        // 8 trackers have 40320 orders, one of 20 shuffles differs unless shuffling is broken
+        let first = TrackerTiers::new(tiers()).urls();
+        assert!((0..20).any(|_| TrackerTiers::new(tiers()).urls() != first));
+    }
+
+    #[test]
+    fn TrackerTiers_promote() {
+        let mut trackers = TrackerTiers::new(tiers());
+        let last_of_tier = trackers.urls()[7].clone();
+
+        trackers.promote(&last_of_tier);
+        assert_eq!(trackers.urls()[0], last_of_tier);
+        assert_eq!(trackers.urls().len(), 9);
+
+        // Trackers never move to another tier
+        trackers.promote("http://b:2/announce");
+        assert_eq!(trackers.urls()[8], "http://b:2/announce");
+        trackers.promote("udp://unknown:1");
+        assert_eq!(trackers.urls().len(), 9);
+    }
+}
@@ -0,0 +1,617 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::io;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time;
+
+use crate::compact_peers;
+use crate::http_tracker::{AnnounceEvent, AnnounceRequest, AnnounceResponse, AnnouncedPeer};
+use crate::metainfo::InfoHash;
+use crate::requests::ScrapeStats;
+
+/// Magic constant opening connect requests
+const PROTOCOL_ID: u64 = 0x41727101980;
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const SCRAPE: u32 = 2;
+const ERROR: u32 = 3;
+
+/// Most info-hashes a single scrape may ask about, so that responses fit in a datagram
+pub const MAX_SCRAPE_HASHES: usize = 74;
+/// Large enough for any request or response of the protocol
+pub const MAX_PACKET_LEN: usize = 2048;
+/// Connection ids may be reused by clients for this long
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Time to wait for the first response, doubled on every retry
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UdpRequest {
+    /// Asks for a connection id, which the other requests have to carry
+    Connect { transaction_id: u32 },
+    Announce {
+        connection_id: u64,
+        transaction_id: u32,
+        request: AnnounceRequest,
+    },
+    Scrape {
+        connection_id: u64,
+        transaction_id: u32,
+        info_hashes: Vec<InfoHash>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UdpResponse {
+    Connect {
+        transaction_id: u32,
+        connection_id: u64,
+    },
+    Announce {
+        transaction_id: u32,
+        response: AnnounceResponse,
+    },
+    /// Statistics in the order of the requested info-hashes
+    Scrape {
+        transaction_id: u32,
+        stats: Vec<ScrapeStats>,
+    },
+    Error {
+        transaction_id: u32,
+        message: String,
+    },
+}
+
+/// Reads big-endian integers from a packet, failing on its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated UDP tracker packet.",
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn hash(&mut self) -> io::Result<[u8; 20]> {
+        Ok(self.take(20)?.try_into().unwrap())
+    }
+}
+
+impl UdpRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            UdpRequest::Connect { transaction_id } => {
+                bytes.extend(PROTOCOL_ID.to_be_bytes());
+                bytes.extend(CONNECT.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+            }
+            UdpRequest::Announce {
+                connection_id,
+                transaction_id,
+                request,
+            } => {
+                let event: u32 = match request.event {
+                    None => 0,
+                    Some(AnnounceEvent::Completed) => 1,
+                    Some(AnnounceEvent::Started) => 2,
+                    Some(AnnounceEvent::Stopped) => 3,
+                };
+                // Only IPv4 addresses fit, others are left for the tracker to detect
+                let ip = match request.ip {
+                    Some(IpAddr::V4(ip)) => u32::from(ip),
+                    _ => 0,
+                };
+
+                bytes.extend(connection_id.to_be_bytes());
+                bytes.extend(ANNOUNCE.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                bytes.extend(request.info_hash);
+                bytes.extend(request.peer_id);
+                bytes.extend(request.downloaded.to_be_bytes());
+                bytes.extend(request.left.to_be_bytes());
+                bytes.extend(request.uploaded.to_be_bytes());
+                bytes.extend(event.to_be_bytes());
+                bytes.extend(ip.to_be_bytes());
+                // Key, identifying the client if its IP address changes
+                bytes.extend(0u32.to_be_bytes());
+                // Number of peers wanted, -1 for the tracker's default
+                let num_want = request
+                    .numwant
+                    .map_or(-1, |numwant| numwant.min(i32::MAX as u32) as i32);
+                bytes.extend(num_want.to_be_bytes());
+                bytes.extend(request.port.to_be_bytes());
+            }
+            UdpRequest::Scrape {
+                connection_id,
+                transaction_id,
+                info_hashes,
+            } => {
+                bytes.extend(connection_id.to_be_bytes());
+                bytes.extend(SCRAPE.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                for info_hash in info_hashes {
+                    bytes.extend(info_hash);
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+        let mut reader = Reader { bytes };
+        let connection_id = reader.u64()?;
+        let action = reader.u32()?;
+        let transaction_id = reader.u32()?;
+
+        match action {
+            CONNECT if connection_id == PROTOCOL_ID => Ok(UdpRequest::Connect { transaction_id }),
+            ANNOUNCE => {
+                let info_hash = reader.hash()?;
+                let peer_id = reader.hash()?;
+                let downloaded = reader.u64()?;
+                let left = reader.u64()?;
+                let uploaded = reader.u64()?;
+                let event = match reader.u32()? {
+                    0 => None,
+                    1 => Some(AnnounceEvent::Completed),
+                    2 => Some(AnnounceEvent::Started),
+                    3 => Some(AnnounceEvent::Stopped),
+                    _ => return Err(invalid("Invalid announce event.")),
+                };
+                let ip = match reader.u32()? {
+                    0 => None,
+                    ip => Some(IpAddr::V4(Ipv4Addr::from(ip))),
+                };
+                let _key = reader.u32()?;
+                let numwant = u32::try_from(reader.u32()? as i32).ok();
+                let port = reader.u16()?;
+
+                Ok(UdpRequest::Announce {
+                    connection_id,
+                    transaction_id,
+                    request: AnnounceRequest {
+                        info_hash,
+                        peer_id,
+                        ip,
+                        port,
+                        uploaded,
+                        downloaded,
+                        left,
+                        event,
+                        numwant,
+                        // Peers are always compact over UDP
+                        compact: true,
+                    },
+                })
+            }
+            SCRAPE => {
+                let hashes = reader.bytes;
+                if hashes.is_empty()
+                    || !hashes.len().is_multiple_of(20)
+                    || hashes.len() / 20 > MAX_SCRAPE_HASHES
+                {
+                    return Err(invalid("Invalid scrape info-hashes."));
+                }
+                Ok(UdpRequest::Scrape {
+                    connection_id,
+                    transaction_id,
+                    info_hashes: hashes
+                        .chunks(20)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                })
+            }
+            _ => Err(invalid("Invalid UDP tracker request.")),
+        }
+    }
+
+    fn transaction_id(&self) -> u32 {
+        match self {
+            UdpRequest::Connect { transaction_id }
+            | UdpRequest::Announce { transaction_id, .. }
+            | UdpRequest::Scrape { transaction_id, .. } => *transaction_id,
+        }
+    }
+}
+
+impl UdpResponse {
+    /// Announce responses list peers in the address family of the socket they're sent over,
+    /// 6 bytes for IPv4 and 18 bytes for IPv6 peers
+    pub fn to_bytes(&self, ipv6: bool) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            UdpResponse::Connect {
+                transaction_id,
+                connection_id,
+            } => {
+                bytes.extend(CONNECT.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                bytes.extend(connection_id.to_be_bytes());
+            }
+            UdpResponse::Announce {
+                transaction_id,
+                response,
+            } => {
+                bytes.extend(ANNOUNCE.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                bytes.extend((response.interval.as_secs() as u32).to_be_bytes());
+                bytes.extend(response.leechers.to_be_bytes());
+                bytes.extend(response.seeders.to_be_bytes());
+                let peers: Vec<_> = response.peers.iter().map(|peer| peer.address).collect();
+                let (peers4, peers6) = compact_peers::encode(&peers);
+                bytes.extend(if ipv6 { peers6 } else { peers4 });
+            }
+            UdpResponse::Scrape {
+                transaction_id,
+                stats,
+            } => {
+                bytes.extend(SCRAPE.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                for stats in stats {
+                    bytes.extend(stats.seeders.to_be_bytes());
+                    bytes.extend(stats.completed.to_be_bytes());
+                    bytes.extend(stats.leechers.to_be_bytes());
+                }
+            }
+            UdpResponse::Error {
+                transaction_id,
+                message,
+            } => {
+                bytes.extend(ERROR.to_be_bytes());
+                bytes.extend(transaction_id.to_be_bytes());
+                bytes.extend(message.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8], ipv6: bool) -> io::Result<Self> {
+        let mut reader = Reader { bytes };
+        let action = reader.u32()?;
+        let transaction_id = reader.u32()?;
+
+        match action {
+            CONNECT => Ok(UdpResponse::Connect {
+                transaction_id,
+                connection_id: reader.u64()?,
+            }),
+            ANNOUNCE => {
+                let interval = Duration::from_secs(reader.u32()? as u64);
+                let leechers = reader.u32()?;
+                let seeders = reader.u32()?;
+                let peers = compact_peers::decode(reader.bytes, ipv6)?
+                    .into_iter()
+                    .map(AnnouncedPeer::from)
+                    .collect();
+
+                Ok(UdpResponse::Announce {
+                    transaction_id,
+                    response: AnnounceResponse {
+                        interval,
+                        seeders,
+                        leechers,
+                        peers,
+                    },
+                })
+            }
+            SCRAPE => {
+                let mut stats = vec![];
+                while !reader.bytes.is_empty() {
+                    stats.push(ScrapeStats {
+                        seeders: reader.u32()?,
+                        completed: reader.u32()?,
+                        leechers: reader.u32()?,
+                    });
+                }
+                Ok(UdpResponse::Scrape {
+                    transaction_id,
+                    stats,
+                })
+            }
+            ERROR => Ok(UdpResponse::Error {
+                transaction_id,
+                message: String::from_utf8_lossy(reader.bytes).into_owned(),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid UDP tracker response.",
+            )),
+        }
+    }
+
+    fn transaction_id(&self) -> u32 {
+        match self {
+            UdpResponse::Connect { transaction_id, .. }
+            | UdpResponse::Announce { transaction_id, .. }
+            | UdpResponse::Scrape { transaction_id, .. }
+            | UdpResponse::Error { transaction_id, .. } => *transaction_id,
+        }
+    }
+}
+
+/// Talks to a single UDP tracker, reusing its connection id while it's valid
+pub struct UdpTrackerClient {
+    socket: UdpSocket,
+    /// Connection id with the time it was received
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTrackerClient {
+    /// Prepares a socket for talking to the tracker at `tracker_addr` (`host:port`)
+    pub async fn new(tracker_addr: &str) -> io::Result<Self> {
+        let tracker_addr = lookup_host(tracker_addr).await?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't resolve {tracker_addr}."),
+            )
+        })?;
+        let local_addr = match tracker_addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(tracker_addr).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+        })
+    }
+
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> io::Result<AnnounceResponse> {
+        let connection_id = self.connection_id().await?;
+        let request = UdpRequest::Announce {
+            connection_id,
+            transaction_id: rand::random(),
+            request: request.clone(),
+        };
+        match self.send(&request).await? {
+            UdpResponse::Announce { response, .. } => Ok(response),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Returns statistics of `info_hashes`' swarms, in the same order
+    pub async fn scrape(&mut self, info_hashes: &[InfoHash]) -> io::Result<Vec<ScrapeStats>> {
+        let connection_id = self.connection_id().await?;
+        let request = UdpRequest::Scrape {
+            connection_id,
+            transaction_id: rand::random(),
+            info_hashes: info_hashes.to_vec(),
+        };
+        match self.send(&request).await? {
+            UdpResponse::Scrape { stats, .. } if stats.len() == info_hashes.len() => Ok(stats),
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Returns the current connection id, asking the tracker for a new one if it expired
+    async fn connection_id(&mut self) -> io::Result<u64> {
+        if let Some((connection_id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+
+        let request = UdpRequest::Connect {
+            transaction_id: rand::random(),
+        };
+        match self.send(&request).await? {
+            UdpResponse::Connect { connection_id, .. } => {
+                self.connection = Some((connection_id, Instant::now()));
+                Ok(connection_id)
+            }
+            _ => Err(io::Error::other("Unexpected tracker response.")),
+        }
+    }
+
+    /// Sends `request` until its response arrives, waiting twice as long after every attempt
+    ///
+    /// Error responses are returned as errors
+    async fn send(&mut self, request: &UdpRequest) -> io::Result<UdpResponse> {
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        let request_bytes = request.to_bytes();
+
+        for attempt in 0..MAX_ATTEMPTS {
+            self.socket.send(&request_bytes).await?;
+
+            let receive = async {
+                let mut buf = [0u8; MAX_PACKET_LEN];
+                loop {
+                    let len = self.socket.recv(&mut buf).await?;
+                    // Late responses to earlier requests are skipped
+                    match UdpResponse::from_bytes(&buf[..len], ipv6) {
+                        Ok(response) if response.transaction_id() == request.transaction_id() => {
+                            return io::Result::Ok(response)
+                        }
+                        _ => continue,
+                    }
+                }
+            };
+
+            match time::timeout(BASE_TIMEOUT * 2u32.pow(attempt), receive).await {
+                Ok(Ok(UdpResponse::Error { message, .. })) => {
+                    // The connection id may have expired at the tracker
+                    self.connection = None;
+                    return Err(io::Error::other(format!("Tracker error: {message}")));
+                }
+                Ok(response) => return response,
+                Err(_) => continue,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "UDP tracker didn't respond.",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)] // to allow structs' original case in test names
+
+    use super::*;
+    use std::str::FromStr;
+
+    fn announce_request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            ip: Some(IpAddr::from_str("10.0.0.1").unwrap()),
+            port: 6881,
+            uploaded: 3,
+            downloaded: 4,
+            left: 5,
+            event: Some(AnnounceEvent::Started),
+            numwant: None,
+            compact: true,
+        }
+    }
+
+    #[test]
+    fn UdpRequest_layout() {
+        let connect = UdpRequest::Connect {
+            transaction_id: 0x01020304,
+        };
+        assert_eq!(
+            connect.to_bytes(),
+            [0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 1, 2, 3, 4]
+        );
+
+        let announce = UdpRequest::Announce {
+            connection_id: 7,
+            transaction_id: 8,
+            request: announce_request(),
+        };
+        let bytes = announce.to_bytes();
+        assert_eq!(bytes.len(), 98);
+        assert_eq!(&bytes[80..84], &[0, 0, 0, 2]);
+        assert_eq!(&bytes[84..88], &[10, 0, 0, 1]);
+        assert_eq!(&bytes[92..96], &(-1i32).to_be_bytes());
+        assert_eq!(&bytes[96..], &6881u16.to_be_bytes());
+
+        for request in [
+            connect,
+            announce,
+            UdpRequest::Announce {
+                connection_id: 7,
+                transaction_id: 8,
+                request: AnnounceRequest {
+                    numwant: Some(30),
+                    ..announce_request()
+                },
+            },
+            UdpRequest::Scrape {
+                connection_id: 7,
+                transaction_id: 8,
+                info_hashes: vec![[1; 20], [2; 20]],
+            },
+        ] {
+            assert_eq!(
+                UdpRequest::from_bytes(&request.to_bytes()).unwrap(),
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn UdpRequest_rejects_malformed_packets() {
+        let mut connect = UdpRequest::Connect { transaction_id: 1 }.to_bytes();
+        connect[0] = 1;
+        assert!(UdpRequest::from_bytes(&connect).is_err());
+
+        let announce = UdpRequest::Announce {
+            connection_id: 7,
+            transaction_id: 8,
+            request: announce_request(),
+        }
+        .to_bytes();
+        assert!(UdpRequest::from_bytes(&announce[..97]).is_err());
+
+        let scrape = UdpRequest::Scrape {
+            connection_id: 7,
+            transaction_id: 8,
+            info_hashes: vec![[1; 20]; MAX_SCRAPE_HASHES + 1],
+        };
+        assert!(UdpRequest::from_bytes(&scrape.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn UdpResponse_roundtrip() {
+        let announce = |peers: Vec<&str>| UdpResponse::Announce {
+            transaction_id: 1,
+            response: AnnounceResponse {
+                interval: Duration::from_secs(60),
+                seeders: 2,
+                leechers: 3,
+                peers: peers
+                    .into_iter()
+                    .map(|peer| AnnouncedPeer {
+                        address: SocketAddr::from_str(peer).unwrap(),
+                        peer_id: None,
+                    })
+                    .collect(),
+            },
+        };
+
+        let ipv4 = announce(vec!["10.0.0.1:6881", "10.0.0.2:6882"]);
+        let bytes = ipv4.to_bytes(false);
+        assert_eq!(bytes.len(), 20 + 2 * 6);
+        assert_eq!(&bytes[20..26], &[10, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(UdpResponse::from_bytes(&bytes, false).unwrap(), ipv4);
+
+        let ipv6 = announce(vec!["[::1]:6881"]);
+        let bytes = ipv6.to_bytes(true);
+        assert_eq!(bytes.len(), 20 + 18);
+        assert_eq!(UdpResponse::from_bytes(&bytes, true).unwrap(), ipv6);
+
+        for response in [
+            UdpResponse::Connect {
+                transaction_id: 1,
+                connection_id: 2,
+            },
+            UdpResponse::Scrape {
+                transaction_id: 1,
+                stats: vec![ScrapeStats {
+                    seeders: 1,
+                    completed: 2,
+                    leechers: 3,
+                }],
+            },
+            UdpResponse::Error {
+                transaction_id: 1,
+                message: "Go away.".to_owned(),
+            },
+        ] {
+            assert_eq!(
+                UdpResponse::from_bytes(&response.to_bytes(false), false).unwrap(),
+                response
+            );
+        }
+    }
+}